@@ -0,0 +1,781 @@
+// 0xFF10 - 0xFF26 and wave RAM at 0xFF30 - 0xFF3F
+//
+// Four channels each put out a 4-bit level: two square waves (the first
+// with a frequency sweep), a wave channel playing 32 samples out of wave
+// RAM and a noise channel driven by an LFSR. Each level goes through its
+// own DAC, then NR51 picks which channels reach each side and NR50 sets the
+// volume of each side.
+//
+// Lengths, envelopes and the sweep are clocked by the frame sequencer,
+// which steps at 512 Hz whenever bit 4 of DIV falls.
+
+use blip::{BlipBuffer, Quality};
+
+pub const CLOCK_RATE: i64 = 4194304;
+
+// Waveforms for the four NRx1 duty settings, played from bit 7 down
+const DUTY_CYCLES: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+
+pub const CHANNELS: usize = 4;
+pub const CHANNEL_NAMES: [&'static str; CHANNELS] = ["square1", "square2", "wave", "noise"];
+
+// Noise periods for each NR43 divisor code, before the shift
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1, for NR10 - NR52. Most of the frequency
+// and length bits are write only.
+const READ_MASKS: [u8; 0x17] = [
+  0x80, 0x3f, 0x00, 0xff, 0xbf,
+  0xff, 0x3f, 0x00, 0xff, 0xbf,
+  0x7f, 0xff, 0x9f, 0xff, 0xbf,
+  0xff, 0xff, 0x00, 0x00, 0xbf,
+  0x00, 0x00, 0x70
+];
+
+// NRx2
+struct Envelope {
+  initial: u8,
+  increase: bool,
+  period: u8,
+  volume: u8,
+  timer: u8
+}
+
+impl Envelope {
+  fn new() -> Envelope {
+    Envelope { initial: 0, increase: false, period: 0, volume: 0, timer: 0 }
+  }
+
+  fn write(&mut self, value: u8) {
+    self.initial = value >> 4;
+    self.increase = value & 0b00001000 != 0;
+    self.period = value & 0b00000111;
+  }
+
+  // The top five bits of NRx2 all being 0 turns the DAC off
+  fn dac_enabled(&self) -> bool {
+    self.initial != 0 || self.increase
+  }
+
+  fn trigger(&mut self) {
+    self.volume = self.initial;
+    self.timer = self.period;
+  }
+
+  fn clock(&mut self) {
+    if self.period == 0 {
+      return;
+    }
+    self.timer = self.timer.saturating_sub(1);
+    if self.timer == 0 {
+      self.timer = self.period;
+      if self.increase && self.volume < 15 {
+        self.volume += 1;
+      } else if !self.increase && self.volume > 0 {
+        self.volume -= 1;
+      }
+    }
+  }
+}
+
+// Counts down to switching the channel off, when enabled in NRx4
+struct Length {
+  counter: u16,
+  max: u16,
+  enabled: bool
+}
+
+impl Length {
+  fn new(max: u16) -> Length {
+    Length { counter: 0, max: max, enabled: false }
+  }
+
+  fn load(&mut self, value: u8) {
+    self.counter = self.max - value as u16;
+  }
+
+  fn trigger(&mut self) {
+    if self.counter == 0 {
+      self.counter = self.max;
+    }
+  }
+
+  // Returns true when the channel should be switched off
+  fn clock(&mut self) -> bool {
+    if self.enabled && self.counter > 0 {
+      self.counter -= 1;
+      return self.counter == 0;
+    }
+    false
+  }
+}
+
+struct Square {
+  enabled: bool,
+  length: Length,
+  envelope: Envelope,
+  duty: u8,
+  position: u8,
+  frequency: u16,
+  timer: i64
+}
+
+impl Square {
+  fn new() -> Square {
+    Square {
+      enabled: false,
+      length: Length::new(64),
+      envelope: Envelope::new(),
+      duty: 0,
+      position: 0,
+      frequency: 0,
+      timer: 0
+    }
+  }
+
+  fn period(&self) -> i64 {
+    (2048 - self.frequency as i64) * 4
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.envelope.dac_enabled();
+    self.length.trigger();
+    self.envelope.trigger();
+    self.timer = self.period();
+  }
+
+  fn step(&mut self, cycles: i64) {
+    self.timer -= cycles;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      self.position = (self.position + 1) & 7;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled && DUTY_CYCLES[self.duty as usize] >> (7 - self.position) & 1 == 1 {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+}
+
+// NR10, only on the first square channel
+struct Sweep {
+  period: u8,
+  negate: bool,
+  shift: u8,
+  timer: u8,
+  enabled: bool,
+  shadow: u16
+}
+
+impl Sweep {
+  fn new() -> Sweep {
+    Sweep { period: 0, negate: false, shift: 0, timer: 0, enabled: false, shadow: 0 }
+  }
+
+  fn write(&mut self, value: u8) {
+    self.period = (value >> 4) & 0b111;
+    self.negate = value & 0b00001000 != 0;
+    self.shift = value & 0b111;
+  }
+
+  // A period of 0 is treated as 8 by the timer
+  fn reload(&mut self) {
+    self.timer = if self.period == 0 { 8 } else { self.period };
+  }
+
+  fn calculate(&self) -> u16 {
+    let delta = self.shadow >> self.shift;
+    if self.negate { self.shadow - delta } else { self.shadow + delta }
+  }
+
+  fn trigger(&mut self, square: &mut Square) {
+    self.shadow = square.frequency;
+    self.reload();
+    self.enabled = self.period != 0 || self.shift != 0;
+    if self.shift != 0 && self.calculate() > 2047 {
+      square.enabled = false;
+    }
+  }
+
+  // Anything that would go past the highest frequency switches the channel
+  // off, and the new frequency is checked again right after being applied
+  fn clock(&mut self, square: &mut Square) {
+    self.timer = self.timer.saturating_sub(1);
+    if self.timer != 0 {
+      return;
+    }
+    self.reload();
+    if !self.enabled || self.period == 0 {
+      return;
+    }
+    let frequency = self.calculate();
+    if frequency > 2047 {
+      square.enabled = false;
+    } else if self.shift != 0 {
+      self.shadow = frequency;
+      square.frequency = frequency;
+      if self.calculate() > 2047 {
+        square.enabled = false;
+      }
+    }
+  }
+}
+
+struct Wave {
+  enabled: bool,
+  dac_enabled: bool,
+  length: Length,
+  // NR32, 0 mutes and 1 - 3 shift the sample right by 0 - 2
+  volume: u8,
+  position: u8,
+  frequency: u16,
+  timer: i64,
+  // Two 4-bit samples per byte, high nibble first
+  ram: [u8; 16]
+}
+
+impl Wave {
+  fn new() -> Wave {
+    Wave {
+      enabled: false,
+      dac_enabled: false,
+      length: Length::new(256),
+      volume: 0,
+      position: 0,
+      frequency: 0,
+      timer: 0,
+      ram: [0; 16]
+    }
+  }
+
+  fn period(&self) -> i64 {
+    (2048 - self.frequency as i64) * 2
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.dac_enabled;
+    self.length.trigger();
+    self.position = 0;
+    self.timer = self.period();
+  }
+
+  fn step(&mut self, cycles: i64) {
+    self.timer -= cycles;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      self.position = (self.position + 1) & 31;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if !self.enabled || self.volume == 0 {
+      return 0;
+    }
+    let byte = self.ram[self.position as usize / 2];
+    let sample = if self.position % 2 == 0 { byte >> 4 } else { byte & 0x0f };
+    sample >> (self.volume - 1)
+  }
+}
+
+struct Noise {
+  enabled: bool,
+  length: Length,
+  envelope: Envelope,
+  shift: u8,
+  // Also feed bit 6 of the LFSR, making a much shorter, buzzier sequence
+  short: bool,
+  divisor: u8,
+  lfsr: u16,
+  timer: i64
+}
+
+impl Noise {
+  fn new() -> Noise {
+    Noise {
+      enabled: false,
+      length: Length::new(64),
+      envelope: Envelope::new(),
+      shift: 0,
+      short: false,
+      divisor: 0,
+      lfsr: 0x7fff,
+      timer: 0
+    }
+  }
+
+  fn write(&mut self, value: u8) {
+    self.shift = value >> 4;
+    self.short = value & 0b00001000 != 0;
+    self.divisor = value & 0b111;
+  }
+
+  fn period(&self) -> i64 {
+    (NOISE_DIVISORS[self.divisor as usize] as i64) << self.shift
+  }
+
+  fn trigger(&mut self) {
+    self.enabled = self.envelope.dac_enabled();
+    self.length.trigger();
+    self.envelope.trigger();
+    self.lfsr = 0x7fff;
+    self.timer = self.period();
+  }
+
+  fn step(&mut self, cycles: i64) {
+    self.timer -= cycles;
+    while self.timer <= 0 {
+      self.timer += self.period();
+      let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+      self.lfsr = (self.lfsr >> 1) | (bit << 14);
+      if self.short {
+        self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+      }
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled && self.lfsr & 1 == 0 {
+      self.envelope.volume
+    } else {
+      0
+    }
+  }
+}
+
+// One stream of samples resampled from the steps in a level, with the
+// capacitor that takes the DACs' DC offset back out
+struct Output {
+  blip: BlipBuffer,
+  // What was last added to the BlipBuffer
+  level: f32,
+  capacitor: f32,
+  charge_factor: f32
+}
+
+impl Output {
+  fn new(quality: Quality, sample_rate: u32) -> Output {
+    let mut output = Output {
+      blip: BlipBuffer::new(quality),
+      level: 0.0,
+      capacitor: 0.0,
+      charge_factor: 0.0
+    };
+    output.set_sample_rate(sample_rate);
+    output
+  }
+
+  fn set_sample_rate(&mut self, sample_rate: u32) {
+    self.blip.set_rates(CLOCK_RATE, sample_rate);
+    self.charge_factor = 0.999958f32.powf(CLOCK_RATE as f32 / sample_rate as f32);
+  }
+
+  fn add_level(&mut self, time: i64, level: f32) {
+    let delta = level - self.level;
+    if delta != 0.0 {
+      self.blip.add_delta(time, delta);
+      self.level = level;
+    }
+  }
+
+  fn end_frame(&mut self, time: i64) -> Vec<f32> {
+    let mut samples = Vec::new();
+    self.blip.end_frame(time, &mut samples);
+    for sample in samples.iter_mut() {
+      let input = *sample;
+      *sample = input - self.capacitor;
+      self.capacitor = input - *sample * self.charge_factor;
+    }
+    samples
+  }
+}
+
+pub struct Apu {
+  // NR52 bit 7. While off every register but NR52 reads 0 and ignores writes.
+  power: bool,
+  // NR10 - NR52 as last written, for reading back
+  registers: [u8; 0x17],
+  square1: Square,
+  sweep: Sweep,
+  square2: Square,
+  wave: Wave,
+  noise: Noise,
+  frame_step: u8,
+  // 0 until there's somewhere for the samples to go
+  sample_rate: u32,
+  quality: Quality,
+  // Left and right, for playing
+  outputs: Vec<Output>,
+  // Cycles since the samples were last taken
+  time: i64,
+  // At a fixed rate of their own, so rate control can't bend the pitch.
+  // Either the mix or each channel on its own.
+  recording: Vec<Output>,
+  record_channels: bool,
+  // One buffer per recording output, filled by take_samples
+  recorded: Vec<Vec<f32>>,
+  // Only changes what goes into the mix
+  muted: [bool; CHANNELS],
+  soloed: [bool; CHANNELS]
+}
+
+impl Apu {
+  pub fn new() -> Apu {
+    // The boot ROM leaves the APU on with every channel on both sides
+    let mut registers = [0; 0x17];
+    registers[0x14] = 0x77;
+    registers[0x15] = 0xf3;
+    Apu {
+      power: true,
+      registers: registers,
+      square1: Square::new(),
+      sweep: Sweep::new(),
+      square2: Square::new(),
+      wave: Wave::new(),
+      noise: Noise::new(),
+      frame_step: 0,
+      sample_rate: 0,
+      quality: Quality::Medium,
+      outputs: vec![Output::new(Quality::Medium, 0), Output::new(Quality::Medium, 0)],
+      time: 0,
+      recording: Vec::new(),
+      record_channels: false,
+      recorded: Vec::new(),
+      muted: [false; CHANNELS],
+      soloed: [false; CHANNELS]
+    }
+  }
+
+  // Can be changed between frames without a click. Doesn't affect
+  // recordings.
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    self.sample_rate = sample_rate;
+    for output in self.outputs.iter_mut() {
+      output.set_sample_rate(sample_rate);
+    }
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  // Throws away anything not yet taken. Recordings already going keep the
+  // quality they started with.
+  pub fn set_quality(&mut self, quality: Quality) {
+    self.quality = quality;
+    let sample_rate = self.sample_rate;
+    self.outputs = vec![Output::new(quality, sample_rate), Output::new(quality, sample_rate)];
+  }
+
+  // Starts resampling a second copy of the output at sample_rate, for
+  // take_recorded_samples. With channels, each channel on its own, ignoring
+  // panning, NR50 and muting. Otherwise the mix, left and right.
+  pub fn start_recording(&mut self, sample_rate: u32, channels: bool) {
+    let count = if channels { CHANNELS } else { 2 };
+    self.recording = (0..count).map(|_| Output::new(self.quality, sample_rate)).collect();
+    self.record_channels = channels;
+    self.recorded = vec![Vec::new(); count];
+  }
+
+  pub fn stop_recording(&mut self) {
+    self.recording.clear();
+    self.record_channels = false;
+    self.recorded.clear();
+  }
+
+  // Toggles whether a channel goes into the mix, returning the new state
+  pub fn toggle_mute(&mut self, channel: usize) -> bool {
+    self.muted[channel] = !self.muted[channel];
+    self.muted[channel]
+  }
+
+  // While any channel is soloed only soloed channels are mixed
+  pub fn toggle_solo(&mut self, channel: usize) -> bool {
+    self.soloed[channel] = !self.soloed[channel];
+    self.soloed[channel]
+  }
+
+  fn audible(&self, channel: usize) -> bool {
+    let soloing = self.soloed.iter().any(|&soloed| soloed);
+    !self.muted[channel] && (!soloing || self.soloed[channel])
+  }
+
+  // Everything generated since the last call, interleaved left and right
+  pub fn take_samples(&mut self) -> Vec<f32> {
+    let left = self.outputs[0].end_frame(self.time);
+    let right = self.outputs[1].end_frame(self.time);
+    for (output, samples) in self.recording.iter_mut().zip(self.recorded.iter_mut()) {
+      samples.extend(output.end_frame(self.time));
+    }
+    self.time = 0;
+    let mut samples = Vec::with_capacity(left.len() * 2);
+    for (left, right) in left.into_iter().zip(right) {
+      samples.push(left);
+      samples.push(right);
+    }
+    samples
+  }
+
+  // One buffer per recording output, as of the last take_samples
+  pub fn take_recorded_samples(&mut self) -> Vec<Vec<f32>> {
+    let empty = vec![Vec::new(); self.recorded.len()];
+    ::std::mem::replace(&mut self.recorded, empty)
+  }
+
+  pub fn step(&mut self, cycles: i64) {
+    if self.sample_rate == 0 && self.recording.is_empty() {
+      self.step_channels(cycles);
+      return;
+    }
+    // An M-cycle at a time, so every change in the output is put in the
+    // BlipBuffers close to when it really happened
+    let mut remaining = cycles;
+    while remaining > 0 {
+      let chunk = if remaining < 4 { remaining } else { 4 };
+      self.step_channels(chunk);
+      self.time += chunk;
+      remaining -= chunk;
+      let levels = self.dac_outputs();
+      let (left, right) = self.mix(&levels);
+      let time = self.time;
+      if self.sample_rate != 0 {
+        self.outputs[0].add_level(time, left);
+        self.outputs[1].add_level(time, right);
+      }
+      if self.record_channels {
+        for (output, level) in self.recording.iter_mut().zip(levels.iter()) {
+          // Half, to leave room for the high pass overshooting
+          output.add_level(time, level / 2.0);
+        }
+      } else if !self.recording.is_empty() {
+        self.recording[0].add_level(time, left);
+        self.recording[1].add_level(time, right);
+      }
+    }
+  }
+
+  fn step_channels(&mut self, cycles: i64) {
+    if self.power {
+      self.square1.step(cycles);
+      self.square2.step(cycles);
+      self.wave.step(cycles);
+      self.noise.step(cycles);
+    }
+  }
+
+  // Called on the falling edge of DIV bit 4
+  pub fn clock_frame_sequencer(&mut self) {
+    if !self.power {
+      return;
+    }
+    if self.frame_step % 2 == 0 {
+      if self.square1.length.clock() {
+        self.square1.enabled = false;
+      }
+      if self.square2.length.clock() {
+        self.square2.enabled = false;
+      }
+      if self.wave.length.clock() {
+        self.wave.enabled = false;
+      }
+      if self.noise.length.clock() {
+        self.noise.enabled = false;
+      }
+    }
+    if self.frame_step == 2 || self.frame_step == 6 {
+      self.sweep.clock(&mut self.square1);
+    }
+    if self.frame_step == 7 {
+      self.square1.envelope.clock();
+      self.square2.envelope.clock();
+      self.noise.envelope.clock();
+    }
+    self.frame_step = (self.frame_step + 1) & 7;
+  }
+
+  pub fn read(&self, address: u16) -> u8 {
+    if address >= 0xff30 && address <= 0xff3f {
+      return self.wave.ram[address as usize - 0xff30];
+    }
+    if address > 0xff26 {
+      return 0xff;
+    }
+    if address == 0xff26 {
+      let mut value = READ_MASKS[0x16];
+      if self.power { value |= 0b10000000; }
+      if self.square1.enabled { value |= 0b0001; }
+      if self.square2.enabled { value |= 0b0010; }
+      if self.wave.enabled { value |= 0b0100; }
+      if self.noise.enabled { value |= 0b1000; }
+      return value;
+    }
+    let index = address as usize - 0xff10;
+    self.registers[index] | READ_MASKS[index]
+  }
+
+  pub fn write(&mut self, address: u16, value: u8) {
+    if address >= 0xff30 && address <= 0xff3f {
+      self.wave.ram[address as usize - 0xff30] = value;
+      return;
+    }
+    if address > 0xff26 {
+      return;
+    }
+    if address == 0xff26 {
+      self.write_power(value & 0b10000000 != 0);
+      return;
+    }
+    if !self.power {
+      // The DMG keeps the length counters running with the power off, and
+      // they can still be loaded
+      match address {
+        0xff11 => self.square1.length.load(value & 0x3f),
+        0xff16 => self.square2.length.load(value & 0x3f),
+        0xff1b => self.wave.length.load(value),
+        0xff20 => self.noise.length.load(value & 0x3f),
+        _ => ()
+      }
+      return;
+    }
+    self.registers[address as usize - 0xff10] = value;
+    match address {
+      // NR10 - NR14
+      0xff10 => self.sweep.write(value),
+      0xff11 => {
+        self.square1.duty = value >> 6;
+        self.square1.length.load(value & 0x3f);
+      },
+      0xff12 => {
+        self.square1.envelope.write(value);
+        if !self.square1.envelope.dac_enabled() {
+          self.square1.enabled = false;
+        }
+      },
+      0xff13 => self.square1.frequency = (self.square1.frequency & 0x700) | value as u16,
+      0xff14 => {
+        self.square1.frequency = (self.square1.frequency & 0xff) | ((value as u16 & 0b111) << 8);
+        self.square1.length.enabled = value & 0b01000000 != 0;
+        if value & 0b10000000 != 0 {
+          self.square1.trigger();
+          self.sweep.trigger(&mut self.square1);
+        }
+      },
+      // NR21 - NR24
+      0xff16 => {
+        self.square2.duty = value >> 6;
+        self.square2.length.load(value & 0x3f);
+      },
+      0xff17 => {
+        self.square2.envelope.write(value);
+        if !self.square2.envelope.dac_enabled() {
+          self.square2.enabled = false;
+        }
+      },
+      0xff18 => self.square2.frequency = (self.square2.frequency & 0x700) | value as u16,
+      0xff19 => {
+        self.square2.frequency = (self.square2.frequency & 0xff) | ((value as u16 & 0b111) << 8);
+        self.square2.length.enabled = value & 0b01000000 != 0;
+        if value & 0b10000000 != 0 {
+          self.square2.trigger();
+        }
+      },
+      // NR30 - NR34
+      0xff1a => {
+        self.wave.dac_enabled = value & 0b10000000 != 0;
+        if !self.wave.dac_enabled {
+          self.wave.enabled = false;
+        }
+      },
+      0xff1b => self.wave.length.load(value),
+      0xff1c => self.wave.volume = (value >> 5) & 0b11,
+      0xff1d => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+      0xff1e => {
+        self.wave.frequency = (self.wave.frequency & 0xff) | ((value as u16 & 0b111) << 8);
+        self.wave.length.enabled = value & 0b01000000 != 0;
+        if value & 0b10000000 != 0 {
+          self.wave.trigger();
+        }
+      },
+      // NR41 - NR44
+      0xff20 => self.noise.length.load(value & 0x3f),
+      0xff21 => {
+        self.noise.envelope.write(value);
+        if !self.noise.envelope.dac_enabled() {
+          self.noise.enabled = false;
+        }
+      },
+      0xff22 => self.noise.write(value),
+      0xff23 => {
+        self.noise.length.enabled = value & 0b01000000 != 0;
+        if value & 0b10000000 != 0 {
+          self.noise.trigger();
+        }
+      },
+      // NR50 and NR51 are only read back when mixing
+      _ => ()
+    }
+  }
+
+  // Powering off clears every register. Powering on restarts the frame
+  // sequencer and the square duty positions.
+  fn write_power(&mut self, power: bool) {
+    if self.power && !power {
+      for address in 0xff10..0xff26 {
+        // Leave the length counters alone, only the duty goes
+        if address == 0xff11 || address == 0xff16 || address == 0xff1b || address == 0xff20 {
+          self.registers[address as usize - 0xff10] = 0;
+        } else {
+          self.write(address, 0);
+        }
+      }
+      self.square1.duty = 0;
+      self.square2.duty = 0;
+      self.square1.enabled = false;
+      self.square2.enabled = false;
+      self.wave.enabled = false;
+      self.noise.enabled = false;
+    } else if !self.power && power {
+      self.frame_step = 0;
+      self.square1.position = 0;
+      self.square2.position = 0;
+    }
+    self.power = power;
+  }
+
+  // Each DAC maps levels 0 - 15 onto 1.0 down to -1.0, or sits at 0 while
+  // it's off. A silent channel with its DAC on still adds an offset.
+  fn dac_outputs(&self) -> [f32; CHANNELS] {
+    let dac = |enabled: bool, level: u8| if enabled { 1.0 - level as f32 / 7.5 } else { 0.0 };
+    [
+      dac(self.square1.envelope.dac_enabled(), self.square1.output()),
+      dac(self.square2.envelope.dac_enabled(), self.square2.output()),
+      dac(self.wave.dac_enabled, self.wave.output()),
+      dac(self.noise.envelope.dac_enabled(), self.noise.output())
+    ]
+  }
+
+  fn mix(&self, outputs: &[f32; CHANNELS]) -> (f32, f32) {
+    let panning = self.registers[0x15];
+    let volume = self.registers[0x14];
+    let mut left = 0.0;
+    let mut right = 0.0;
+    for (channel, output) in outputs.iter().enumerate() {
+      if !self.audible(channel) {
+        continue;
+      }
+      if panning & (0b00010000 << channel) != 0 {
+        left += *output;
+      }
+      if panning & (0b00000001 << channel) != 0 {
+        right += *output;
+      }
+    }
+    // Scale into -1.0 - 1.0, NR50 goes from 1/8 to full volume
+    let left_volume = ((volume >> 4) & 0b111) as f32 + 1.0;
+    let right_volume = (volume & 0b111) as f32 + 1.0;
+    (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+  }
+}
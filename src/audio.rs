@@ -0,0 +1,139 @@
+use cpal;
+use futures::stream::Stream;
+use futures::task::{self, Executor, Run};
+use std::collections::VecDeque;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+// Plays the APU's samples on the default output device. cpal wants its own
+// thread to run the event loop on, the emulator hands it samples through a
+// shared queue.
+
+struct InlineExecutor;
+
+impl Executor for InlineExecutor {
+  fn execute(&self, run: Run) {
+    run.run();
+  }
+}
+
+pub struct Audio {
+  pub sample_rate: u32,
+  // Interleaved left and right
+  queue: Arc<Mutex<VecDeque<f32>>>
+}
+
+impl Audio {
+  // None if there's nothing to play on, we just run silently then
+  pub fn open() -> Option<Audio> {
+    let queue = Arc::new(Mutex::new(VecDeque::new()));
+    let stream_queue = queue.clone();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      let endpoint = match cpal::get_default_endpoint() {
+        Some(endpoint) => endpoint,
+        None => {
+          let _ = sender.send(Err("no output device".to_string()));
+          return;
+        }
+      };
+      let format = match endpoint.get_supported_formats_list().ok().and_then(|mut formats| formats.next()) {
+        Some(format) => format,
+        None => {
+          let _ = sender.send(Err("no supported format".to_string()));
+          return;
+        }
+      };
+      let event_loop = cpal::EventLoop::new();
+      let (mut voice, stream) = match cpal::Voice::new(&endpoint, &format, &event_loop) {
+        Ok(voice) => voice,
+        Err(err) => {
+          let _ = sender.send(Err(format!("{:?}", err)));
+          return;
+        }
+      };
+      let _ = sender.send(Ok(format.samples_rate.0));
+
+      let channels = format.channels.len();
+      let executor = Arc::new(InlineExecutor);
+      let mut last = (0.0, 0.0);
+      task::spawn(stream.for_each(move |buffer| -> Result<_, ()> {
+        match buffer {
+          cpal::UnknownTypeBuffer::U16(mut buffer) => {
+            fill(&mut buffer, channels, &stream_queue, &mut last, |value| ((value * 0.5 + 0.5) * ::std::u16::MAX as f32) as u16);
+          },
+          cpal::UnknownTypeBuffer::I16(mut buffer) => {
+            fill(&mut buffer, channels, &stream_queue, &mut last, |value| (value * ::std::i16::MAX as f32) as i16);
+          },
+          cpal::UnknownTypeBuffer::F32(mut buffer) => {
+            fill(&mut buffer, channels, &stream_queue, &mut last, |value| value);
+          }
+        }
+        Ok(())
+      })).execute(executor);
+      voice.play();
+      event_loop.run();
+    });
+
+    match receiver.recv() {
+      Ok(Ok(sample_rate)) => {
+        info!("Playing audio at {} Hz", sample_rate);
+        Some(Audio {
+          sample_rate: sample_rate,
+          queue: queue
+        })
+      },
+      Ok(Err(err)) => {
+        warn!("Could not open audio output, running without sound: {}", err);
+        None
+      },
+      Err(_) => {
+        warn!("Audio thread died, running without sound");
+        None
+      }
+    }
+  }
+
+  pub fn queue_samples(&self, samples: &[f32]) {
+    let mut queue = self.queue.lock().unwrap();
+    queue.extend(samples.iter().cloned());
+    // Anything past the capacity would only add latency
+    let limit = self.capacity() * 2;
+    while queue.len() > limit {
+      queue.pop_front();
+      queue.pop_front();
+    }
+  }
+
+  // In stereo frames, 100ms worth
+  pub fn capacity(&self) -> usize {
+    self.sample_rate as usize / 10
+  }
+
+  // Stereo frames waiting to be played
+  pub fn buffered(&self) -> usize {
+    self.queue.lock().unwrap().len() / 2
+  }
+}
+
+// Mono devices get both sides averaged, anything past stereo gets silence.
+// If we run dry the last sample is held rather than clicking back to 0.
+fn fill<T, F>(buffer: &mut [T], channels: usize, queue: &Mutex<VecDeque<f32>>,
+              last: &mut (f32, f32), convert: F)
+  where F: Fn(f32) -> T {
+  let mut queue = queue.lock().unwrap();
+  for frame in buffer.chunks_mut(channels) {
+    if queue.len() >= 2 {
+      *last = (queue.pop_front().unwrap(), queue.pop_front().unwrap());
+    }
+    let (left, right) = *last;
+    for (channel, out) in frame.iter_mut().enumerate() {
+      *out = convert(match (channels, channel) {
+        (1, _) => (left + right) / 2.0,
+        (_, 0) => left,
+        (_, 1) => right,
+        _ => 0.0
+      });
+    }
+  }
+}
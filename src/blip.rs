@@ -0,0 +1,283 @@
+use std::f64::consts::PI;
+
+// Band-limited step synthesis, along the lines of blip_buf.
+//
+// The APU's output is a series of steps at exact clock times. Sampling it
+// directly at 44.1/48 kHz aliases all the harmonics above Nyquist back into
+// the audible range. Instead every step is added as a band-limited impulse
+// into a buffer of differences, and reading integrates them back into
+// levels. The impulse is a windowed sinc, precomputed for PHASES fractional
+// sample positions.
+
+const PHASES: usize = 64;
+// Points per sample used when integrating the impulse
+const INTEGRATION_STEPS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quality {
+  Low,
+  Medium,
+  High
+}
+
+impl Quality {
+  // Length of the impulse in output samples, longer gives a sharper cutoff
+  fn taps(&self) -> usize {
+    match *self {
+      Quality::Low => 8,
+      Quality::Medium => 16,
+      Quality::High => 32
+    }
+  }
+
+  // Cutoff as a fraction of Nyquist. Shorter kernels roll off more slowly
+  // so they need to start earlier to keep aliases out.
+  fn cutoff(&self) -> f64 {
+    match *self {
+      Quality::Low => 0.65,
+      Quality::Medium => 0.8,
+      Quality::High => 0.9
+    }
+  }
+}
+
+pub struct BlipBuffer {
+  taps: usize,
+  // PHASES + 1 rows of taps weights
+  kernel: Vec<f32>,
+  // Output samples per clock
+  factor: f64,
+  // Where clock 0 of the current frame falls, in samples from buffer[0]
+  offset: f64,
+  // Differences, not levels
+  buffer: Vec<f32>,
+  integrator: f32
+}
+
+impl BlipBuffer {
+  pub fn new(quality: Quality) -> BlipBuffer {
+    let taps = quality.taps();
+    let cutoff = quality.cutoff();
+    // Sample index + k of an impulse is this many samples after it, less
+    // the fractional position. Output lags by this much.
+    let delay = (taps / 2 - 1) as f64;
+    let half = (taps / 2) as f64;
+    // One extra row, a whole sample later, to interpolate towards
+    let mut kernel = Vec::with_capacity((PHASES + 1) * taps);
+    for phase in 0..PHASES + 1 {
+      // What's stored are differences between neighbouring samples of the
+      // band-limited step, so each weight is the impulse integrated over
+      // the sample before it
+      let row = (0..taps).map(|k| {
+        let t = k as f64 - delay - phase as f64 / PHASES as f64;
+        (0..INTEGRATION_STEPS).map(|step| {
+          let t = t - 1.0 + (step as f64 + 0.5) / INTEGRATION_STEPS as f64;
+          sinc(cutoff * t) * blackman(t / half)
+        }).sum::<f64>()
+      }).collect::<Vec<_>>();
+      // Every phase has to add up to exactly one step
+      let sum: f64 = row.iter().sum();
+      kernel.extend(row.iter().map(|weight| (weight / sum) as f32));
+    }
+    BlipBuffer {
+      taps: taps,
+      kernel: kernel,
+      factor: 0.0,
+      offset: 0.0,
+      buffer: Vec::new(),
+      integrator: 0.0
+    }
+  }
+
+  // Can change between frames without disturbing what's buffered
+  pub fn set_rates(&mut self, clock_rate: i64, sample_rate: u32) {
+    self.factor = sample_rate as f64 / clock_rate as f64;
+  }
+
+  // A step of delta, time clocks into the current frame
+  pub fn add_delta(&mut self, time: i64, delta: f32) {
+    let position = self.offset + time as f64 * self.factor;
+    let scaled = position * PHASES as f64;
+    let index = scaled as usize / PHASES;
+    let phase = scaled as usize % PHASES;
+    // Linear interpolation between the two nearest phases
+    let between = (scaled - scaled.floor()) as f32;
+    if self.buffer.len() < index + self.taps {
+      self.buffer.resize(index + self.taps, 0.0);
+    }
+    let before = &self.kernel[phase * self.taps..(phase + 1) * self.taps];
+    let after = &self.kernel[(phase + 1) * self.taps..(phase + 2) * self.taps];
+    let samples = self.buffer[index..].iter_mut();
+    for (sample, (before, after)) in samples.zip(before.iter().zip(after)) {
+      *sample += delta * (before + (after - before) * between);
+    }
+  }
+
+  // Ends the frame after time clocks and appends every sample that no later
+  // step can affect anymore. The next frame starts where this one ended.
+  pub fn end_frame(&mut self, time: i64, out: &mut Vec<f32>) {
+    let end = self.offset + time as f64 * self.factor;
+    let count = end as usize;
+    if self.buffer.len() < count {
+      self.buffer.resize(count, 0.0);
+    }
+    for difference in self.buffer.drain(..count) {
+      self.integrator += difference;
+      out.push(self.integrator);
+    }
+    self.offset = end - count as f64;
+  }
+}
+
+fn sinc(x: f64) -> f64 {
+  if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+// Over -1 to 1
+fn blackman(x: f64) -> f64 {
+  if x.abs() > 1.0 {
+    0.0
+  } else {
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CLOCK_RATE: i64 = 4194304;
+  const SAMPLE_RATE: u32 = 48000;
+  // Square wave edges are this many clocks apart. Keeping them on whole
+  // clocks like the APU's means the reference doesn't need rounding, and a
+  // prime keeps the harmonics from lining up with the sample rate.
+  const HALF_PERIOD: i64 = 1699;
+  const FREQUENCY: f64 = CLOCK_RATE as f64 / (2 * HALF_PERIOD) as f64;
+  const FFT_SIZE: usize = 8192;
+
+  // A -0.5 to 0.5 square wave, fed in frames the way the APU does
+  fn square_wave(quality: Quality) -> Vec<f32> {
+    let mut blip = BlipBuffer::new(quality);
+    blip.set_rates(CLOCK_RATE, SAMPLE_RATE);
+    let frame = 4096;
+    let mut samples = Vec::new();
+    let mut edge = 0;
+    let mut frame_start = 0;
+    let mut level = -0.5;
+    while samples.len() < FFT_SIZE * 2 {
+      let frame_end = frame_start + frame;
+      loop {
+        let time = edge * HALF_PERIOD;
+        if time >= frame_end {
+          break;
+        }
+        blip.add_delta(time - frame_start, -2.0 * level);
+        level = -level;
+        edge += 1;
+      }
+      blip.end_frame(frame, &mut samples);
+      frame_start = frame_end;
+    }
+    samples
+  }
+
+  // Magnitude of each bin up to Nyquist, Blackman-Harris windowed and
+  // scaled so a full scale sine reads 1.0
+  fn spectrum(samples: &[f32]) -> Vec<f64> {
+    let window = |n: usize| {
+      let x = 2.0 * PI * n as f64 / (FFT_SIZE - 1) as f64;
+      0.35875 - 0.48829 * x.cos() + 0.14128 * (2.0 * x).cos() - 0.01168 * (3.0 * x).cos()
+    };
+    let windowed = samples[..FFT_SIZE].iter().enumerate()
+      .map(|(n, sample)| *sample as f64 * window(n)).collect::<Vec<_>>();
+    let gain: f64 = (0..FFT_SIZE).map(&window).sum();
+    // A plain DFT, turning a phasor by one bin's angle each sample
+    (0..FFT_SIZE / 2).map(|bin| {
+      let angle = 2.0 * PI * bin as f64 / FFT_SIZE as f64;
+      let (step_re, step_im) = (angle.cos(), -angle.sin());
+      let (mut phasor_re, mut phasor_im) = (1.0, 0.0);
+      let (mut re, mut im) = (0.0, 0.0);
+      for value in &windowed {
+        re += value * phasor_re;
+        im += value * phasor_im;
+        let turned = phasor_re * step_re - phasor_im * step_im;
+        phasor_im = phasor_re * step_im + phasor_im * step_re;
+        phasor_re = turned;
+      }
+      2.0 * (re * re + im * im).sqrt() / gain
+    }).collect()
+  }
+
+  fn bin_of(frequency: f64) -> f64 {
+    frequency * FFT_SIZE as f64 / SAMPLE_RATE as f64
+  }
+
+  // The reference is an ideal band-limited square wave, the odd harmonics
+  // below Nyquist at 2/(pi k) each, sampled directly
+  fn reference() -> Vec<f32> {
+    let nyquist = SAMPLE_RATE as f64 / 2.0;
+    (0..FFT_SIZE * 2).map(|n| {
+      let t = n as f64 / SAMPLE_RATE as f64;
+      let mut value = 0.0;
+      let mut harmonic = 1.0;
+      while FREQUENCY * harmonic < nyquist {
+        value += 2.0 / (PI * harmonic) * (2.0 * PI * FREQUENCY * harmonic * t).sin();
+        harmonic += 2.0;
+      }
+      value as f32
+    }).collect()
+  }
+
+  // Checks the harmonics well inside the passband match the reference, and
+  // that nothing else in the audible band (aliases, mostly) gets above
+  // floor_db
+  fn check(quality: Quality, passband: f64, floor_db: f64) {
+    // Skip the start, where the kernel is still filling up
+    let magnitudes = spectrum(&square_wave(quality)[FFT_SIZE / 2..]);
+    let expected = spectrum(&reference()[FFT_SIZE / 2..]);
+    let peak = |magnitudes: &[f64], frequency: f64| {
+      let center = bin_of(frequency).round() as usize;
+      magnitudes[center - 3..center + 4].iter().cloned().fold(0.0, f64::max)
+    };
+
+    let mut harmonic = 1;
+    while FREQUENCY * harmonic as f64 <= passband {
+      let frequency = FREQUENCY * harmonic as f64;
+      let error_db = 20.0 * (peak(&magnitudes, frequency) / peak(&expected, frequency)).log10();
+      assert!(error_db.abs() < 0.5, "{:?}: harmonic {} is off by {:.2} dB", quality, harmonic, error_db);
+      harmonic += 2;
+    }
+
+    let audible = bin_of(20000.0) as usize;
+    let near_harmonic = |bin: usize| {
+      let harmonic = (bin as f64 / bin_of(FREQUENCY)).round();
+      (harmonic as usize) % 2 == 1 && (bin as f64 - bin_of(FREQUENCY * harmonic)).abs() < 8.0
+    };
+    let mut worst = 0.0;
+    let mut worst_bin = 0;
+    for bin in 8..audible {
+      if !near_harmonic(bin) && magnitudes[bin] > worst {
+        worst = magnitudes[bin];
+        worst_bin = bin;
+      }
+    }
+    let worst_db = 20.0 * (worst / (2.0 / PI)).log10();
+    assert!(worst_db < floor_db, "{:?}: {:.1} dB at {:.0} Hz, wanted under {} dB",
+            quality, worst_db, worst_bin as f64 * SAMPLE_RATE as f64 / FFT_SIZE as f64, floor_db);
+  }
+
+  #[test]
+  fn low_quality_spectrum() {
+    check(Quality::Low, 6500.0, -60.0);
+  }
+
+  #[test]
+  fn medium_quality_spectrum() {
+    check(Quality::Medium, 15000.0, -80.0);
+  }
+
+  #[test]
+  fn high_quality_spectrum() {
+    check(Quality::High, 18000.0, -90.0);
+  }
+}
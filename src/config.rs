@@ -0,0 +1,300 @@
+use glutin::VirtualKeyCode;
+use apu::CHANNEL_NAMES;
+use blip::Quality;
+use joypad::Button;
+use palette::Palette;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+
+pub const CONFIG_PATH: &'static str = "bamegoy.cfg";
+
+// Read from bamegoy.cfg in the working directory. Every line is
+// `setting = value`, # starts a comment unless it's the start of a #rrggbb
+// color. For example:
+//
+//   a = X
+//   b = Z
+//   start = Return
+//   select = Back
+//   palette = gray
+//   palette = #e0f8d0 #88c070 #346856 #081820
+//   access_restrictions = off
+//   sync = video
+//   audio_quality = high
+//   mute_wave = F3
+//   solo_noise = F8
+//   record_mix = F9
+//   record_frames = 600
+pub struct Config {
+  pub keys: HashMap<VirtualKeyCode, Button>,
+  pub hotkeys: HashMap<VirtualKeyCode, Hotkey>,
+  pub palette: Palette,
+  // Block the CPU from VRAM and OAM while the PPU is using them, like the
+  // hardware does. Turning it off helps when debugging.
+  pub access_restrictions: bool,
+  pub sync: SyncMode,
+  // How hard to work at keeping aliasing out of the audio
+  pub audio_quality: Quality,
+  // How long a recording runs, in video frames
+  pub record_frames: u32
+}
+
+// Keys that control the emulator rather than the game
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+  // By channel number, in apu::CHANNEL_NAMES order
+  Mute(usize),
+  Solo(usize),
+  // Start or stop writing the mix to a WAV file
+  RecordMix,
+  // Same, but a file per channel
+  RecordChannels
+}
+
+// What paces the emulator
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncMode {
+  // Run a touch faster or slower to keep the sound card fed
+  Audio,
+  // Run by the wall clock, resampling the audio slightly to keep up
+  Video
+}
+
+impl Hotkey {
+  // The name it's bound by in the config file
+  fn setting(&self) -> String {
+    match *self {
+      Hotkey::Mute(channel) => format!("mute_{}", CHANNEL_NAMES[channel]),
+      Hotkey::Solo(channel) => format!("solo_{}", CHANNEL_NAMES[channel]),
+      Hotkey::RecordMix => "record_mix".to_string(),
+      Hotkey::RecordChannels => "record_channels".to_string()
+    }
+  }
+}
+
+impl Config {
+  pub fn default() -> Config {
+    let mut keys = HashMap::new();
+    keys.insert(VirtualKeyCode::Right, Button::Right);
+    keys.insert(VirtualKeyCode::Left, Button::Left);
+    keys.insert(VirtualKeyCode::Up, Button::Up);
+    keys.insert(VirtualKeyCode::Down, Button::Down);
+    keys.insert(VirtualKeyCode::X, Button::A);
+    keys.insert(VirtualKeyCode::Z, Button::B);
+    keys.insert(VirtualKeyCode::Back, Button::Select);
+    keys.insert(VirtualKeyCode::Return, Button::Start);
+    let mut hotkeys = HashMap::new();
+    hotkeys.insert(VirtualKeyCode::F1, Hotkey::Mute(0));
+    hotkeys.insert(VirtualKeyCode::F2, Hotkey::Mute(1));
+    hotkeys.insert(VirtualKeyCode::F3, Hotkey::Mute(2));
+    hotkeys.insert(VirtualKeyCode::F4, Hotkey::Mute(3));
+    hotkeys.insert(VirtualKeyCode::F5, Hotkey::Solo(0));
+    hotkeys.insert(VirtualKeyCode::F6, Hotkey::Solo(1));
+    hotkeys.insert(VirtualKeyCode::F7, Hotkey::Solo(2));
+    hotkeys.insert(VirtualKeyCode::F8, Hotkey::Solo(3));
+    hotkeys.insert(VirtualKeyCode::F9, Hotkey::RecordMix);
+    hotkeys.insert(VirtualKeyCode::F10, Hotkey::RecordChannels);
+    Config {
+      keys: keys,
+      hotkeys: hotkeys,
+      palette: Palette::classic_green(),
+      access_restrictions: true,
+      sync: SyncMode::Audio,
+      audio_quality: Quality::Medium,
+      // A minute
+      record_frames: 3600
+    }
+  }
+
+  // Falls back to the defaults for anything the file doesn't mention,
+  // including the whole file not existing
+  pub fn load(path: &str) -> Config {
+    let mut config = Config::default();
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+      Ok(_) => (),
+      Err(ref err) if err.kind() == io::ErrorKind::NotFound => return config,
+      Err(err) => {
+        warn!("Could not read {}: {}", path, err);
+        return config;
+      }
+    }
+    for (number, line) in contents.lines().enumerate() {
+      let line = strip_comment(line).trim();
+      if line.is_empty() {
+        continue;
+      }
+      let mut parts = line.splitn(2, '=');
+      let setting = parts.next().unwrap().trim();
+      let value = match parts.next() {
+        Some(value) => value.trim(),
+        None => {
+          warn!("{}:{}: expected `setting = value`", path, number + 1);
+          continue;
+        }
+      };
+      if let Err(err) = config.set(setting, value) {
+        warn!("{}:{}: {}", path, number + 1, err);
+      }
+    }
+    config
+  }
+
+  fn set(&mut self, setting: &str, value: &str) -> Result<(), String> {
+    match setting {
+      "palette" => {
+        self.palette = Palette::parse(value)?;
+        return Ok(());
+      },
+      "access_restrictions" => {
+        self.access_restrictions = parse_bool(value)?;
+        return Ok(());
+      },
+      "sync" => {
+        self.sync = match value {
+          "audio" => SyncMode::Audio,
+          "video" => SyncMode::Video,
+          _ => return Err(format!("expected audio or video but got `{}`", value))
+        };
+        return Ok(());
+      },
+      "audio_quality" => {
+        self.audio_quality = match value {
+          "low" => Quality::Low,
+          "medium" => Quality::Medium,
+          "high" => Quality::High,
+          _ => return Err(format!("expected low, medium or high but got `{}`", value))
+        };
+        return Ok(());
+      },
+      "record_frames" => {
+        self.record_frames = match value.parse() {
+          Ok(frames) if frames > 0 => frames,
+          _ => return Err(format!("expected a number of frames but got `{}`", value))
+        };
+        return Ok(());
+      },
+      _ => ()
+    }
+    if let Some(hotkey) = parse_hotkey(setting) {
+      let key = match parse_key(value) {
+        Some(key) => key,
+        None => return Err(format!("unknown key `{}`", value))
+      };
+      // A key pressing a button and toggling something at the same time is
+      // never what anyone wants
+      if let Some(button) = self.keys.get(&key) {
+        return Err(format!("`{}` is already bound to {}, rebind that first", value, button_setting(*button)));
+      }
+      self.hotkeys.retain(|_, bound| *bound != hotkey);
+      self.hotkeys.insert(key, hotkey);
+      return Ok(());
+    }
+    match parse_button(setting) {
+      Some(button) => {
+        let key = match parse_key(value) {
+          Some(key) => key,
+          None => return Err(format!("unknown key `{}`", value))
+        };
+        if let Some(hotkey) = self.hotkeys.get(&key) {
+          return Err(format!("`{}` is already bound to {}, rebind that first", value, hotkey.setting()));
+        }
+        // Rebinding a button replaces its default key
+        self.keys.retain(|_, bound| *bound != button);
+        self.keys.insert(key, button);
+        Ok(())
+      },
+      None => Err(format!("unknown setting `{}`", setting))
+    }
+  }
+}
+
+// Cuts the line at the first # that isn't part of a color
+fn strip_comment(line: &str) -> &str {
+  let bytes = line.as_bytes();
+  for (index, &byte) in bytes.iter().enumerate() {
+    if byte != b'#' {
+      continue;
+    }
+    let hex = |byte: Option<&u8>| match byte {
+      Some(&byte) => (byte as char).is_digit(16),
+      None => false
+    };
+    let color = (1..7).all(|offset| hex(bytes.get(index + offset))) && !hex(bytes.get(index + 7));
+    if !color {
+      return &line[..index];
+    }
+  }
+  line
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+  match value {
+    "on" | "true" | "yes" => Ok(true),
+    "off" | "false" | "no" => Ok(false),
+    _ => Err(format!("expected on or off but got `{}`", value))
+  }
+}
+
+fn parse_button(name: &str) -> Option<Button> {
+  match name {
+    "right" => Some(Button::Right),
+    "left" => Some(Button::Left),
+    "up" => Some(Button::Up),
+    "down" => Some(Button::Down),
+    "a" => Some(Button::A),
+    "b" => Some(Button::B),
+    "select" => Some(Button::Select),
+    "start" => Some(Button::Start),
+    _ => None
+  }
+}
+
+// The other way round from parse_button
+fn button_setting(button: Button) -> String {
+  format!("{:?}", button).to_lowercase()
+}
+
+// mute_<channel>, solo_<channel>, record_mix or record_channels
+fn parse_hotkey(name: &str) -> Option<Hotkey> {
+  match name {
+    "record_mix" => return Some(Hotkey::RecordMix),
+    "record_channels" => return Some(Hotkey::RecordChannels),
+    _ => ()
+  }
+  let channel = |name: &str| CHANNEL_NAMES.iter().position(|channel| *channel == name);
+  if name.starts_with("mute_") {
+    channel(&name[5..]).map(Hotkey::Mute)
+  } else if name.starts_with("solo_") {
+    channel(&name[5..]).map(Hotkey::Solo)
+  } else {
+    None
+  }
+}
+
+// Names match the VirtualKeyCode variants
+fn parse_key(name: &str) -> Option<VirtualKeyCode> {
+  use glutin::VirtualKeyCode::*;
+  let key = match name {
+    "A" => A, "B" => B, "C" => C, "D" => D, "E" => E, "F" => F, "G" => G,
+    "H" => H, "I" => I, "J" => J, "K" => K, "L" => L, "M" => M, "N" => N,
+    "O" => O, "P" => P, "Q" => Q, "R" => R, "S" => S, "T" => T, "U" => U,
+    "V" => V, "W" => W, "X" => X, "Y" => Y, "Z" => Z,
+    "Key0" => Key0, "Key1" => Key1, "Key2" => Key2, "Key3" => Key3, "Key4" => Key4,
+    "Key5" => Key5, "Key6" => Key6, "Key7" => Key7, "Key8" => Key8, "Key9" => Key9,
+    "Numpad0" => Numpad0, "Numpad1" => Numpad1, "Numpad2" => Numpad2, "Numpad3" => Numpad3,
+    "Numpad4" => Numpad4, "Numpad5" => Numpad5, "Numpad6" => Numpad6, "Numpad7" => Numpad7,
+    "Numpad8" => Numpad8, "Numpad9" => Numpad9,
+    "Up" => Up, "Down" => Down, "Left" => Left, "Right" => Right,
+    "Return" => Return, "Space" => Space, "Back" => Back, "Tab" => Tab, "Escape" => Escape,
+    "LShift" => LShift, "RShift" => RShift, "LControl" => LControl, "RControl" => RControl,
+    "LAlt" => LAlt, "RAlt" => RAlt,
+    "F1" => F1, "F2" => F2, "F3" => F3, "F4" => F4, "F5" => F5, "F6" => F6,
+    "F7" => F7, "F8" => F8, "F9" => F9, "F10" => F10, "F11" => F11, "F12" => F12,
+    "Comma" => Comma, "Period" => Period, "Semicolon" => Semicolon, "Slash" => Slash,
+    _ => return None
+  };
+  Some(key)
+}
@@ -21,13 +21,14 @@ bitflags! {
   }
 }
 
-enum Interrupt {
-  VBlank  = 0x0040,
-  LCDStat = 0x0048,
-  Timer   = 0x0050,
-  Serial  = 0x0058,
-  Joypad  = 0x0060
-}
+// Highest priority first
+const INTERRUPTS: [(InterruptFlags, u16); 5] = [
+  (VBLANK,   0x0040),
+  (LCD_STAT, 0x0048),
+  (TIMER,    0x0050),
+  (SERIAL,   0x0058),
+  (JOYPAD,   0x0060)
+];
 
 pub struct CPU {
   a: u8,
@@ -41,7 +42,11 @@ pub struct CPU {
   stack_pointer: u16,
   program_counter: u16,
   transition_enable_interrupts: bool,
-  interrupts: bool // IME
+  interrupts: bool, // IME
+  locked: bool, // Set by illegal opcodes, only a reset gets out of this
+  halted: bool, // Waiting for IE & IF
+  stopped: bool, // Waiting for a button press
+  halt_bug: bool // The next fetch doesn't move the PC
 }
 
 impl CPU {
@@ -58,61 +63,102 @@ impl CPU {
       stack_pointer: 0xfffe,
       program_counter: 0x100,
       transition_enable_interrupts: false,
-      interrupts: true
+      interrupts: true,
+      locked: false,
+      halted: false,
+      stopped: false,
+      halt_bug: false
     }
   }
 
-  pub fn step(&mut self, memory: &mut Memory) -> i64 {    
-    // Interrupts
-    {
-      let mut active_interrupt: Option<Interrupt> = None;
-
-      let mut ifs = InterruptFlags::from_bits_truncate(memory.read_byte(0xff0f));
-      let ies = InterruptFlags::from_bits_truncate(memory.read_byte(0xffff));
-
-      if ifs.contains(VBLANK) && ies.contains(VBLANK) {
-        active_interrupt = Some(Interrupt::VBlank);
-        ifs.remove(VBLANK);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(LCD_STAT) && ies.contains(LCD_STAT) {
-        active_interrupt = Some(Interrupt::LCDStat);
-        ifs.remove(LCD_STAT);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(TIMER) && ies.contains(TIMER) {
-        active_interrupt = Some(Interrupt::Timer);
-        ifs.remove(TIMER);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(SERIAL) && ies.contains(SERIAL) {
-        active_interrupt = Some(Interrupt::Serial);
-        ifs.remove(SERIAL);
-        memory.write_byte(0xff0f, ifs.bits);
-      } else if ifs.contains(JOYPAD) && ies.contains(JOYPAD) {
-        active_interrupt = Some(Interrupt::Joypad);
-        ifs.remove(JOYPAD);
-        memory.write_byte(0xff0f, ifs.bits);
-      }
+  // Dispatch takes 5 M-cycles: two idle, two pushing PC and one jumping.
+  // Which interrupt wins is only decided after the high byte of PC has been
+  // pushed, so if that push lands on IE it can change the outcome. If it
+  // leaves nothing pending the dispatch is cancelled and we end up at 0x0000
+  // with IF untouched.
+  fn service_interrupt(&mut self, memory: &mut Memory) -> i64 {
+    self.interrupts = false;
+    self.transition_enable_interrupts = false;
+    memory.cycle_idle();
+    memory.cycle_idle();
+    let pc = self.program_counter;
+    self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+    memory.cycle_write(self.stack_pointer, pc.hi());
+    // Anything requested up to here, the timer overflowing during the idle
+    // cycles say, still gets a say
+    let requested = memory.read_byte(0xff0f);
+    let pending = InterruptFlags::from_bits_truncate(memory.read_byte(0xffff) & requested);
+    self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+    memory.cycle_write(self.stack_pointer, pc.lo());
 
-      if self.interrupts {
-        if let Some(interrupt) = active_interrupt {
-          let pc = self.program_counter;
-          self.push_short(memory, pc);
-          self.program_counter = interrupt as u16;
-          self.interrupts = false;
-          return 80;
-        }
+    self.program_counter = 0x0000;
+    for &(flag, vector) in INTERRUPTS.iter() {
+      if pending.contains(flag) {
+        // Only now is the request acknowledged. IF is read again since
+        // something else may have been requested while pushing the low byte.
+        let now = memory.read_byte(0xff0f);
+        memory.write_byte(0xff0f, now & !flag.bits);
+        self.program_counter = vector;
+        break;
       }
+    }
+    20
+  }
 
-      if self.transition_enable_interrupts {
-        self.transition_enable_interrupts = false;
-        self.interrupts = true;
+  // In STOP nothing is clocked, not even the timer or the PPU
+  pub fn stopped(&self) -> bool {
+    self.stopped
+  }
+
+  // Runs one instruction, or services an interrupt, clocking the rest of the
+  // bus along with it. Returns how many cycles that took.
+  pub fn step(&mut self, memory: &mut Memory) -> i64 {
+    let cycles = self.execute(memory);
+    memory.finish_instruction(cycles);
+    cycles
+  }
+
+  fn execute(&mut self, memory: &mut Memory) -> i64 {
+    if self.locked {
+      return 4;
+    }
+    // Any selected button pulls its P1 line low, and that wakes us up
+    if self.stopped {
+      if memory.read_byte(0xff00) & 0x0f == 0x0f {
+        // Not even the clock runs
+        return 0;
       }
+      self.stopped = false;
+    }
+    // A pending interrupt ends HALT even with IME off, in which case it just
+    // carries on without servicing it
+    let mut wake_cycles = 0;
+    if self.halted {
+      if !interrupt_pending(memory) {
+        return 4;
+      }
+      self.halted = false;
+      memory.cycle_idle();
+      wake_cycles = 4;
+    }
+    // Interrupts
+    if self.interrupts && interrupt_pending(memory) {
+      return wake_cycles + self.service_interrupt(memory);
+    }
+    // EI only takes effect after the instruction following it
+    if self.transition_enable_interrupts {
+      self.transition_enable_interrupts = false;
+      self.interrupts = true;
     }
     // Fetch
-    let opcode: u8 = memory.read_byte(self.program_counter);
-    println!("{:02x} at address {:04x}", opcode, self.program_counter);
-    assert!(self.program_counter <= 0x7FFF);
+    let opcode: u8 = memory.cycle_read(self.program_counter);
+    trace!("{:02x} at address {:04x}", opcode, self.program_counter);
     // Increment
-    self.program_counter = self.program_counter.wrapping_add(1);
+    if self.halt_bug {
+      self.halt_bug = false;
+    } else {
+      self.program_counter = self.program_counter.wrapping_add(1);
+    }
     // Execute
     match opcode {
       0x00 => {
@@ -127,8 +173,7 @@ impl CPU {
       },
       0x02 => {
         // LD (BC),A
-        let value = memory.read_byte((self.b as u16) << 8 | self.c as u16);
-        self.a = value;
+        memory.cycle_write(self.bc(), self.a);
         8
       },
       0x03 => {
@@ -151,25 +196,29 @@ impl CPU {
       },
       0x07 => {
         // RLCA
-        let old_carry = (self.f.bits & CARRY.bits) >> 4; // 0 or 1
         self.f.remove(ZERO);
         self.f.remove(SUBTRACT);
         self.f.remove(HALF_CARRY);
         self.f.set(CARRY, (self.a & 0x80) == 0x80);
-        self.a <<= 1;
-        self.a |= old_carry;
+        self.a = self.a.rotate_left(1);
         4
       },
+      0x08 => {
+        // LD (a16),SP
+        let dest = self.read_short_immediate(memory);
+        memory.cycle_write(dest, self.stack_pointer.lo());
+        memory.cycle_write(dest.wrapping_add(1), self.stack_pointer.hi());
+        20
+      },
       0x09 => {
         // ADD HL,BC
-        let orig = self.hl();
-        let val = self.hl().wrapping_add(self.bc());
-        self.h = val.hi();
-        self.l = val.lo();
-        let res = self.hl();
-        self.f.remove(SUBTRACT);
-        self.f.set(HALF_CARRY, (orig ^ val ^ res) & 0x100 == 0x100);
-        self.f.set(CARRY, res < orig);
+        let value = self.bc();
+        self.add_hl(value);
+        8
+      },
+      0x0a => {
+        // LD A,(BC)
+        self.a = memory.cycle_read(self.bc());
         8
       },
       0x0b => {
@@ -190,12 +239,34 @@ impl CPU {
         self.c = value;
         8
       },
+      0x0f => {
+        // RRCA
+        self.f.remove(ZERO);
+        self.f.remove(SUBTRACT);
+        self.f.remove(HALF_CARRY);
+        self.f.set(CARRY, (self.a & 0x01) == 0x01);
+        self.a = self.a.rotate_right(1);
+        4
+      },
+      0x10 => {
+        // STOP
+        // Skips a padding byte and resets DIV like any write to it would
+        self.program_counter = self.program_counter.wrapping_add(1);
+        memory.write_byte(0xff04, 0);
+        self.stopped = true;
+        4
+      },
       0x11 => {
         // LD DE,d16
         self.e = self.read_byte_immediate(memory);
         self.d = self.read_byte_immediate(memory);
         12
       },
+      0x12 => {
+        // LD (DE),A
+        memory.cycle_write(self.de(), self.a);
+        8
+      },
       0x13 => {
         // INC DE
         inc_double_r8(&mut self.d, &mut self.e)
@@ -208,15 +279,38 @@ impl CPU {
         // DEC D
         dec_r8(&mut self.d, &mut self.f)
       },
+      0x16 => {
+        // LD n into D
+        let value = self.read_byte_immediate(memory);
+        self.d = value;
+        8
+      },
+      0x17 => {
+        // RLA
+        let old_carry = (self.f.bits & CARRY.bits) >> 4; // 0 or 1
+        self.f.remove(ZERO);
+        self.f.remove(SUBTRACT);
+        self.f.remove(HALF_CARRY);
+        self.f.set(CARRY, (self.a & 0x80) == 0x80);
+        self.a <<= 1;
+        self.a |= old_carry;
+        4
+      },
       0x18 => {
         // JR
         let rel_target = self.read_signed_byte_immediate(memory);
         self.relative_jump(rel_target);
         12
       },
+      0x19 => {
+        // ADD HL,DE
+        let value = self.de();
+        self.add_hl(value);
+        8
+      },
       0x1a => {
         // LD A,(DE)
-        self.a = memory.read_byte(self.de());
+        self.a = memory.cycle_read(self.de());
         8
       },
       0x1b => {
@@ -231,29 +325,39 @@ impl CPU {
         // DEC E
         dec_r8(&mut self.e, &mut self.f)
       },
+      0x1e => {
+        // LD n into E
+        let value = self.read_byte_immediate(memory);
+        self.e = value;
+        8
+      },
+      0x1f => {
+        // RRA
+        let old_carry = (self.f.bits & CARRY.bits) << 3; // 0 or 0x80
+        self.f.remove(ZERO);
+        self.f.remove(SUBTRACT);
+        self.f.remove(HALF_CARRY);
+        self.f.set(CARRY, (self.a & 0x01) == 0x01);
+        self.a >>= 1;
+        self.a |= old_carry;
+        4
+      },
       0x20 => {
         // JR NZ
-        let rel_target = self.read_signed_byte_immediate(memory);
-        if !self.f.contains(ZERO) {
-          self.relative_jump(rel_target);
-          12
-        } else {
-          8
-        }
+        let condition = !self.f.contains(ZERO);
+        self.relative_jump_if(memory, condition)
       },
       0x21 => {
         // LD nn into HL
         let value = self.read_short_immediate(memory);
-        self.h = value.hi();
-        self.l = value.lo();
+        self.set_hl(value);
         12
       },
       0x22 => {
         // LD (HL+),A
-        memory.write_byte(self.hl(), self.a);
+        memory.cycle_write(self.hl(), self.a);
         let val = self.hl().wrapping_add(1);
-        self.h = val.hi();
-        self.l = val.lo();
+        self.set_hl(val);
         8
       },
       0x23 => {
@@ -268,22 +372,50 @@ impl CPU {
         // DEC H
         dec_r8(&mut self.h, &mut self.f)
       },
+      0x26 => {
+        // LD n into H
+        let value = self.read_byte_immediate(memory);
+        self.h = value;
+        8
+      },
+      0x27 => {
+        // DAA
+        let mut correction = 0;
+        let mut carry = false;
+        let subtract = self.f.contains(SUBTRACT);
+        if self.f.contains(HALF_CARRY) || (!subtract && (self.a & 0x0f) > 0x09) {
+          correction |= 0x06;
+        }
+        if self.f.contains(CARRY) || (!subtract && self.a > 0x99) {
+          correction |= 0x60;
+          carry = true;
+        }
+        self.a = if subtract {
+          self.a.wrapping_sub(correction)
+        } else {
+          self.a.wrapping_add(correction)
+        };
+        self.f.set(ZERO, self.a == 0);
+        self.f.remove(HALF_CARRY);
+        self.f.set(CARRY, carry);
+        4
+      },
       0x28 => {
         // JR Z,r8
-        let rel_target = self.read_signed_byte_immediate(memory);
-        if self.f.contains(ZERO) {
-          self.relative_jump(rel_target);
-          12
-        } else {
-          8
-        }
+        let condition = self.f.contains(ZERO);
+        self.relative_jump_if(memory, condition)
+      },
+      0x29 => {
+        // ADD HL,HL
+        let value = self.hl();
+        self.add_hl(value);
+        8
       },
       0x2a => {
         // LD A,(HL+)
-        self.a = memory.read_byte(self.hl());
+        self.a = memory.cycle_read(self.hl());
         let val = self.hl().wrapping_add(1);
-        self.h = val.hi();
-        self.l = val.lo();
+        self.set_hl(val);
         8
       },
       0x2b => {
@@ -298,6 +430,12 @@ impl CPU {
         // DEC L
         dec_r8(&mut self.l, &mut self.f)
       },
+      0x2e => {
+        // LD n into L
+        let value = self.read_byte_immediate(memory);
+        self.l = value;
+        8
+      },
       0x2f => {
         // CPL A
         self.a = !self.a;
@@ -305,6 +443,11 @@ impl CPU {
         self.f.insert(HALF_CARRY);
         4
       },
+      0x30 => {
+        // JR NC,r8
+        let condition = !self.f.contains(CARRY);
+        self.relative_jump_if(memory, condition)
+      },
       0x31 => {
         // LD SP,d16
         let value = self.read_short_immediate(memory);
@@ -313,9 +456,9 @@ impl CPU {
       },
       0x32 => {
         // LD (HL-),A
-        let result = (self.a as u16).wrapping_sub(1);
-        self.h = result.hi();
-        self.l = result.lo();
+        memory.cycle_write(self.hl(), self.a);
+        let val = self.hl().wrapping_sub(1);
+        self.set_hl(val);
         8
       },
       0x33 => {
@@ -325,49 +468,50 @@ impl CPU {
       },
       0x34 => {
         // INC (HL)
-        let orig = self.read_byte_immediate(memory);
-        let value = orig.wrapping_add(1);
         let destination = self.hl();
-        memory.write_byte(destination, value);
-        self.f.set(ZERO, value == 0);
-        self.f.remove(SUBTRACT);
-        self.f.set(HALF_CARRY, (orig ^ 1 ^ value & 0x10) == 0x10);
+        let mut value = memory.cycle_read(destination);
+        inc_r8(&mut value, &mut self.f);
+        memory.cycle_write(destination, value);
         12
       },
       0x35 => {
         // DEC (HL)
-        let orig = self.read_byte_immediate(memory);
-        let value = orig.wrapping_sub(1);
         let destination = self.hl();
-        memory.write_byte(destination, value);
-        self.f.set(ZERO, value == 0);
-        self.f.insert(SUBTRACT);
-        self.f.set(HALF_CARRY, (orig ^ !1 ^ value & 0x10) == 0x10);
+        let mut value = memory.cycle_read(destination);
+        dec_r8(&mut value, &mut self.f);
+        memory.cycle_write(destination, value);
         12
       },
       0x36 => {
         // LD (HL),d8
         let value = self.read_byte_immediate(memory);
         let destination = self.hl();
-        memory.write_byte(destination, value);
+        memory.cycle_write(destination, value);
         12
       },
+      0x37 => {
+        // SCF
+        self.f.remove(SUBTRACT);
+        self.f.remove(HALF_CARRY);
+        self.f.insert(CARRY);
+        4
+      },
       0x38 => {
         // JR C,r8
-        let rel_target = self.read_signed_byte_immediate(memory);
-        if self.f.contains(CARRY) {
-          self.relative_jump(rel_target);
-          12
-        } else {
-          8
-        }
+        let condition = self.f.contains(CARRY);
+        self.relative_jump_if(memory, condition)
+      },
+      0x39 => {
+        // ADD HL,SP
+        let value = self.stack_pointer;
+        self.add_hl(value);
+        8
       },
       0x3a => {
         // LD A,(HL-)
-        self.a = memory.read_byte(self.hl());
+        self.a = memory.cycle_read(self.hl());
         let val = self.hl().wrapping_sub(1);
-        self.h = val.hi();
-        self.l = val.lo();
+        self.set_hl(val);
         8
       },
       0x3b => {
@@ -389,6 +533,14 @@ impl CPU {
         self.a = result;
         8
       },
+      0x3f => {
+        // CCF
+        let carry = self.f.contains(CARRY);
+        self.f.remove(SUBTRACT);
+        self.f.remove(HALF_CARRY);
+        self.f.set(CARRY, !carry);
+        4
+      },
       0x40 => {
         // LD B,B
         // self.b = self.b;
@@ -419,6 +571,11 @@ impl CPU {
         self.b = self.l;
         4
       },
+      0x46 => {
+        // LD B,(HL)
+        self.b = memory.cycle_read(self.hl());
+        8
+      },
       0x47 => {
         // LD B,A
         self.b = self.a;
@@ -454,6 +611,11 @@ impl CPU {
         self.c = self.l;
         4
       },
+      0x4e => {
+        // LD C,(HL)
+        self.c = memory.cycle_read(self.hl());
+        8
+      },
       0x4f => {
         // LD C,A
         self.c = self.a;
@@ -491,7 +653,7 @@ impl CPU {
       },
       0x56 => {
         // LD D,(HL)
-        self.d = memory.read_byte(self.hl());
+        self.d = memory.cycle_read(self.hl());
         8
       },
       0x57 => {
@@ -531,7 +693,7 @@ impl CPU {
       },
       0x5e => {
         // LD E,(HL)
-        self.e = memory.read_byte(self.hl());
+        self.e = memory.cycle_read(self.hl());
         8
       },
       0x5f => {
@@ -569,6 +731,11 @@ impl CPU {
         self.h = self.l;
         4
       },
+      0x66 => {
+        // LD H,(HL)
+        self.h = memory.cycle_read(self.hl());
+        8
+      },
       0x67 => {
         // LD H,A
         self.h = self.a;
@@ -604,14 +771,61 @@ impl CPU {
         // self.l = self.l;
         4
       },
+      0x6e => {
+        // LD L,(HL)
+        self.l = memory.cycle_read(self.hl());
+        8
+      },
       0x6f => {
         // LD L,A
         self.l = self.a;
         4
       },
+      0x70 => {
+        // LD (HL),B
+        memory.cycle_write(self.hl(), self.b);
+        8
+      },
+      0x71 => {
+        // LD (HL),C
+        memory.cycle_write(self.hl(), self.c);
+        8
+      },
+      0x72 => {
+        // LD (HL),D
+        memory.cycle_write(self.hl(), self.d);
+        8
+      },
+      0x73 => {
+        // LD (HL),E
+        memory.cycle_write(self.hl(), self.e);
+        8
+      },
+      0x74 => {
+        // LD (HL),H
+        memory.cycle_write(self.hl(), self.h);
+        8
+      },
+      0x75 => {
+        // LD (HL),L
+        memory.cycle_write(self.hl(), self.l);
+        8
+      },
+      0x76 => {
+        // HALT
+        // With IME off and an interrupt already pending the CPU doesn't halt
+        // at all, instead it fails to increment the PC on the next fetch so
+        // the following byte gets read twice
+        if !self.interrupts && interrupt_pending(memory) {
+          self.halt_bug = true;
+        } else {
+          self.halted = true;
+        }
+        4
+      },
       0x77 => {
         // LD (HL),A
-        memory.write_byte(self.hl(), self.a);
+        memory.cycle_write(self.hl(), self.a);
         8
       },
       0x78 => {
@@ -623,6 +837,7 @@ impl CPU {
         // LD A,C
         self.a = self.c;
         4
+      },
       0x7a => {
         // LD A,D
         self.a = self.d;
@@ -645,7 +860,7 @@ impl CPU {
       },
       0x7e => {
         // LD A,(HL)
-        self.a = memory.read_byte(self.hl());
+        self.a = memory.cycle_read(self.hl());
         8
       },
       0x7f => {
@@ -653,111 +868,345 @@ impl CPU {
         // self.a = self.a;
         4
       },
+      0x80 => {
+        // ADD A,B
+        add_a(&mut self.a, &mut self.f, self.b);
+        4
+      },
+      0x81 => {
+        // ADD A,C
+        add_a(&mut self.a, &mut self.f, self.c);
+        4
+      },
+      0x82 => {
+        // ADD A,D
+        add_a(&mut self.a, &mut self.f, self.d);
+        4
+      },
       0x83 => {
         // ADD A,E
-        let original = self.a;
-        self.a = self.a.wrapping_add(self.e);
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.set(HALF_CARRY, (original ^ self.e ^ self.a) & 0x10 == 0x10);
-        self.f.set(CARRY, self.a < original);
+        add_a(&mut self.a, &mut self.f, self.e);
+        4
+      },
+      0x84 => {
+        // ADD A,H
+        add_a(&mut self.a, &mut self.f, self.h);
+        4
+      },
+      0x85 => {
+        // ADD A,L
+        add_a(&mut self.a, &mut self.f, self.l);
+        4
+      },
+      0x86 => {
+        // ADD A,(HL)
+        let value = memory.cycle_read(self.hl());
+        add_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0x87 => {
+        // ADD A,A
+        let value = self.a;
+        add_a(&mut self.a, &mut self.f, value);
+        4
+      },
+      0x88 => {
+        // ADC A,B
+        adc_a(&mut self.a, &mut self.f, self.b);
+        4
+      },
+      0x89 => {
+        // ADC A,C
+        adc_a(&mut self.a, &mut self.f, self.c);
+        4
+      },
+      0x8a => {
+        // ADC A,D
+        adc_a(&mut self.a, &mut self.f, self.d);
+        4
+      },
+      0x8b => {
+        // ADC A,E
+        adc_a(&mut self.a, &mut self.f, self.e);
+        4
+      },
+      0x8c => {
+        // ADC A,H
+        adc_a(&mut self.a, &mut self.f, self.h);
+        4
+      },
+      0x8d => {
+        // ADC A,L
+        adc_a(&mut self.a, &mut self.f, self.l);
         4
       },
       0x8e => {
-        // ADC (HL)
-        let original = self.a;
-        let value = memory.read_byte(self.hl()).wrapping_add((self.f.bits & CARRY.bits) >> 4);
-        self.a = self.a.wrapping_add(value);
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.set(HALF_CARRY, (original ^ value ^ self.a) & 0x10 == 0x10);
-        self.f.set(CARRY, self.a < original);
+        // ADC A,(HL)
+        let value = memory.cycle_read(self.hl());
+        adc_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0x8f => {
+        // ADC A,A
+        let value = self.a;
+        adc_a(&mut self.a, &mut self.f, value);
+        4
+      },
+      0x90 => {
+        // SUB B
+        sub_a(&mut self.a, &mut self.f, self.b);
+        4
+      },
+      0x91 => {
+        // SUB C
+        sub_a(&mut self.a, &mut self.f, self.c);
+        4
+      },
+      0x92 => {
+        // SUB D
+        sub_a(&mut self.a, &mut self.f, self.d);
+        4
+      },
+      0x93 => {
+        // SUB E
+        sub_a(&mut self.a, &mut self.f, self.e);
+        4
+      },
+      0x94 => {
+        // SUB H
+        sub_a(&mut self.a, &mut self.f, self.h);
+        4
+      },
+      0x95 => {
+        // SUB L
+        sub_a(&mut self.a, &mut self.f, self.l);
+        4
+      },
+      0x96 => {
+        // SUB (HL)
+        let value = memory.cycle_read(self.hl());
+        sub_a(&mut self.a, &mut self.f, value);
         8
       },
+      0x97 => {
+        // SUB A
+        let value = self.a;
+        sub_a(&mut self.a, &mut self.f, value);
+        4
+      },
+      0x98 => {
+        // SBC A,B
+        sbc_a(&mut self.a, &mut self.f, self.b);
+        4
+      },
       0x99 => {
         // SBC A,C
-        let original = self.a;
-        let value = self.c.wrapping_add((self.f.bits & CARRY.bits) >> 4);
-        self.a = self.a.wrapping_add(value);
-        self.f.set(ZERO, self.a == 0);
-        self.f.insert(SUBTRACT);
-        self.f.set(HALF_CARRY, (original ^ value ^ self.a) & 0x10 == 0x10);
-        self.f.set(CARRY, self.a < original);
+        sbc_a(&mut self.a, &mut self.f, self.c);
         4
       },
-      0xa2 => {
+      0x9a => {
+        // SBC A,D
+        sbc_a(&mut self.a, &mut self.f, self.d);
+        4
+      },
+      0x9b => {
+        // SBC A,E
+        sbc_a(&mut self.a, &mut self.f, self.e);
+        4
+      },
+      0x9c => {
+        // SBC A,H
+        sbc_a(&mut self.a, &mut self.f, self.h);
+        4
+      },
+      0x9d => {
+        // SBC A,L
+        sbc_a(&mut self.a, &mut self.f, self.l);
+        4
+      },
+      0x9e => {
+        // SBC A,(HL)
+        let value = memory.cycle_read(self.hl());
+        sbc_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0x9f => {
+        // SBC A,A
+        let value = self.a;
+        sbc_a(&mut self.a, &mut self.f, value);
+        4
+      },
+      0xa0 => {
+        // AND B
+        and_a(&mut self.a, &mut self.f, self.b);
+        4
+      },
+      0xa1 => {
         // AND C
-        self.a &= self.c;
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.insert(HALF_CARRY);
-        self.f.remove(CARRY);
+        and_a(&mut self.a, &mut self.f, self.c);
+        4
+      },
+      0xa2 => {
+        // AND D
+        and_a(&mut self.a, &mut self.f, self.d);
         4
       },
       0xa3 => {
         // AND E
-        self.a &= self.e;
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.insert(HALF_CARRY);
-        self.f.remove(CARRY);
+        and_a(&mut self.a, &mut self.f, self.e);
+        4
+      },
+      0xa4 => {
+        // AND H
+        and_a(&mut self.a, &mut self.f, self.h);
+        4
+      },
+      0xa5 => {
+        // AND L
+        and_a(&mut self.a, &mut self.f, self.l);
         4
       },
+      0xa6 => {
+        // AND (HL)
+        let value = memory.cycle_read(self.hl());
+        and_a(&mut self.a, &mut self.f, value);
+        8
+      },
       0xa7 => {
         // AND A
-        // self.a &= self.a;
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.insert(HALF_CARRY);
-        self.f.remove(CARRY);
+        let value = self.a;
+        and_a(&mut self.a, &mut self.f, value);
+        4
+      },
+      0xa8 => {
+        // XOR B
+        xor_a(&mut self.a, &mut self.f, self.b);
         4
       },
       0xa9 => {
         // XOR C
-        self.a ^= self.c;
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.remove(CARRY);
+        xor_a(&mut self.a, &mut self.f, self.c);
+        4
+      },
+      0xaa => {
+        // XOR D
+        xor_a(&mut self.a, &mut self.f, self.d);
+        4
+      },
+      0xab => {
+        // XOR E
+        xor_a(&mut self.a, &mut self.f, self.e);
+        4
+      },
+      0xac => {
+        // XOR H
+        xor_a(&mut self.a, &mut self.f, self.h);
         4
       },
+      0xad => {
+        // XOR L
+        xor_a(&mut self.a, &mut self.f, self.l);
+        4
+      },
+      0xae => {
+        // XOR (HL)
+        let value = memory.cycle_read(self.hl());
+        xor_a(&mut self.a, &mut self.f, value);
+        8
+      },
       0xaf => {
         // XOR A with A
-        //self.a ^= self.a;
-        self.a = 0;
-        self.f.insert(ZERO);
-        self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.remove(CARRY);
+        let value = self.a;
+        xor_a(&mut self.a, &mut self.f, value);
         4
       },
-      0xb1 => {
+      0xb0 => {
         // OR B
-        self.a |= self.b;
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.remove(CARRY);
+        or_a(&mut self.a, &mut self.f, self.b);
+        4
+      },
+      0xb1 => {
+        // OR C
+        or_a(&mut self.a, &mut self.f, self.c);
+        4
+      },
+      0xb2 => {
+        // OR D
+        or_a(&mut self.a, &mut self.f, self.d);
+        4
+      },
+      0xb3 => {
+        // OR E
+        or_a(&mut self.a, &mut self.f, self.e);
+        4
+      },
+      0xb4 => {
+        // OR H
+        or_a(&mut self.a, &mut self.f, self.h);
+        4
+      },
+      0xb5 => {
+        // OR L
+        or_a(&mut self.a, &mut self.f, self.l);
+        4
+      },
+      0xb6 => {
+        // OR (HL)
+        let value = memory.cycle_read(self.hl());
+        or_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0xb7 => {
+        // OR A
+        let value = self.a;
+        or_a(&mut self.a, &mut self.f, value);
+        4
+      },
+      0xb8 => {
+        // CP B
+        cp_a(self.a, &mut self.f, self.b);
+        4
+      },
+      0xb9 => {
+        // CP C
+        cp_a(self.a, &mut self.f, self.c);
+        4
+      },
+      0xba => {
+        // CP D
+        cp_a(self.a, &mut self.f, self.d);
+        4
+      },
+      0xbb => {
+        // CP E
+        cp_a(self.a, &mut self.f, self.e);
+        4
+      },
+      0xbc => {
+        // CP H
+        cp_a(self.a, &mut self.f, self.h);
         4
       },
       0xbd => {
         // CP L
-        self.f.set(ZERO, self.a == self.l);
-        self.f.insert(SUBTRACT);
-        self.f.set(HALF_CARRY, (self.a ^ self.l ^ self.a.wrapping_sub(self.l)) & 0x10 == 0x10);
-        self.f.set(CARRY, self.a > self.l);
+        cp_a(self.a, &mut self.f, self.l);
+        4
+      },
+      0xbe => {
+        // CP (HL)
+        let value = memory.cycle_read(self.hl());
+        cp_a(self.a, &mut self.f, value);
+        8
+      },
+      0xbf => {
+        // CP A
+        cp_a(self.a, &mut self.f, self.a);
         4
       },
       0xc0 => {
         // RET NZ
-        let dest = self.pop_short(memory);
-        if !self.f.contains(ZERO) {
-          self.program_counter = dest;
-          20
-        } else {
-          self.push_short(memory, dest);
-          8
-        }
+        let condition = !self.f.contains(ZERO);
+        self.return_if(memory, condition)
       },
       0xc1 => {
         // POP BC
@@ -765,39 +1214,42 @@ impl CPU {
         self.b = self.pop_byte(memory);
         12
       },
+      0xc2 => {
+        // JP NZ,a16
+        let condition = !self.f.contains(ZERO);
+        self.jump_if(memory, condition)
+      },
       0xc3 => {
         // JMP nn
-        let target = memory.read_short(self.program_counter);
+        let target = self.read_short_immediate(memory);
         self.program_counter = target;
         16
       },
       0xc4 => {
         // CALL NZ,a16
-        let target = self.read_short_immediate(memory);
-        if !self.f.contains(ZERO) {
-          let pc = self.program_counter;
-          self.push_short(memory, pc);
-          self.program_counter = target;
-          24
-        } else {
-          12
-        }
+        let condition = !self.f.contains(ZERO);
+        self.call_if(memory, condition)
       },
       0xc5 => {
         // PUSH BC
-        let b = self.b;
-        self.push_byte(memory, b);
-        let c = self.c;
-        self.push_byte(memory, c);
+        let bc = self.bc();
+        self.push_short(memory, bc);
         16
       },
-      0xcd => {
-        // CALL a16
-        let target = self.read_short_immediate(memory);
-        let pc = self.program_counter;
-        self.push_short(memory, pc);
-        self.program_counter = target;
-        24
+      0xc6 => {
+        // ADD A,d8
+        let value = self.read_byte_immediate(memory);
+        add_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0xc7 => {
+        // RST 00H
+        self.restart(memory, 0x0000)
+      },
+      0xc8 => {
+        // RET Z
+        let condition = self.f.contains(ZERO);
+        self.return_if(memory, condition)
       },
       0xc9 => {
         // RET
@@ -805,24 +1257,108 @@ impl CPU {
         self.program_counter = dest;
         16
       },
+      0xca => {
+        // JP Z,a16
+        let condition = self.f.contains(ZERO);
+        self.jump_if(memory, condition)
+      },
       0xcb => {
         // CB
         let next_opcode = self.read_byte_immediate(memory);
-        // TODO: is this 4 plus right? starting to think not
-        4 + self.cb(next_opcode)
+        self.cb(memory, next_opcode)
+      },
+      0xcc => {
+        // CALL Z,a16
+        let condition = self.f.contains(ZERO);
+        self.call_if(memory, condition)
+      },
+      0xcd => {
+        // CALL a16
+        self.call_if(memory, true)
+      },
+      0xce => {
+        // ADC A,d8
+        let value = self.read_byte_immediate(memory);
+        adc_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0xcf => {
+        // RST 08H
+        self.restart(memory, 0x0008)
+      },
+      0xd0 => {
+        // RET NC
+        let condition = !self.f.contains(CARRY);
+        self.return_if(memory, condition)
+      },
+      0xd1 => {
+        // POP DE
+        self.e = self.pop_byte(memory);
+        self.d = self.pop_byte(memory);
+        12
+      },
+      0xd2 => {
+        // JP NC,a16
+        let condition = !self.f.contains(CARRY);
+        self.jump_if(memory, condition)
+      },
+      0xd4 => {
+        // CALL NC,a16
+        let condition = !self.f.contains(CARRY);
+        self.call_if(memory, condition)
       },
       0xd5 => {
         // PUSH DE
-        let d = self.d;
-        self.push_byte(memory, d);
-        let e = self.e;
-        self.push_byte(memory, e);
+        let de = self.de();
+        self.push_short(memory, de);
         16
       },
+      0xd6 => {
+        // SUB d8
+        let value = self.read_byte_immediate(memory);
+        sub_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0xd7 => {
+        // RST 10H
+        self.restart(memory, 0x0010)
+      },
+      0xd8 => {
+        // RET C
+        let condition = self.f.contains(CARRY);
+        self.return_if(memory, condition)
+      },
+      0xd9 => {
+        // RETI
+        let dest = self.pop_short(memory);
+        self.program_counter = dest;
+        self.interrupts = true;
+        16
+      },
+      0xda => {
+        // JP C,a16
+        let condition = self.f.contains(CARRY);
+        self.jump_if(memory, condition)
+      },
+      0xdc => {
+        // CALL C,a16
+        let condition = self.f.contains(CARRY);
+        self.call_if(memory, condition)
+      },
+      0xde => {
+        // SBC A,d8
+        let value = self.read_byte_immediate(memory);
+        sbc_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0xdf => {
+        // RST 18H
+        self.restart(memory, 0x0018)
+      },
       0xe0 => {
         // LDH n,A
         let offset = self.read_byte_immediate(memory);
-        memory.write_byte(0xFF00 + offset as u16, self.a);
+        memory.cycle_write(0xFF00 + offset as u16, self.a);
         12
       },
       0xe1 => {
@@ -833,32 +1369,29 @@ impl CPU {
       },
       0xe2 => {
         // LD (C),A
-        memory.write_byte(0xFF00 + self.c as u16, self.a);
+        memory.cycle_write(0xFF00 + self.c as u16, self.a);
         8
       },
       0xe5 => {
         // PUSH HL
-        let h = self.h;
-        self.push_byte(memory, h);
-        let l = self.l;
-        self.push_byte(memory, l);
+        let hl = self.hl();
+        self.push_short(memory, hl);
         16
       },
       0xe6 => {
         // AND d8
-        let val = self.read_byte_immediate(memory);
-        self.a &= val;
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.insert(HALF_CARRY);
-        self.f.remove(CARRY);
+        let value = self.read_byte_immediate(memory);
+        and_a(&mut self.a, &mut self.f, value);
         8
       },
       0xe7 => {
         // RST 20H
-        let pc = self.program_counter;
-        self.push_short(memory, pc);
-        self.program_counter = 0x0020;
+        self.restart(memory, 0x0020)
+      },
+      0xe8 => {
+        // ADD SP,r8
+        let offset = self.read_signed_byte_immediate(memory);
+        self.stack_pointer = self.offset_stack_pointer(offset);
         16
       },
       0xe9 => {
@@ -869,23 +1402,23 @@ impl CPU {
       0xea => {
         // LD a16,A
         let dest = self.read_short_immediate(memory);
-        memory.write_byte(dest, self.a);
+        memory.cycle_write(dest, self.a);
         16
       },
       0xee => {
         // XOR d8
-        let val = self.read_byte_immediate(memory);
-        self.a ^= val;
-        self.f.set(ZERO, self.a == 0);
-        self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.remove(CARRY);
+        let value = self.read_byte_immediate(memory);
+        xor_a(&mut self.a, &mut self.f, value);
         8
       },
+      0xef => {
+        // RST 28H
+        self.restart(memory, 0x0028)
+      },
       0xf0 => {
         // LDH A,n
         let offset = self.read_byte_immediate(memory);
-        self.a = memory.read_byte(0xFF00 + offset as u16);
+        self.a = memory.cycle_read(0xFF00 + offset as u16);
         12
       },
       0xf1 => {
@@ -894,6 +1427,11 @@ impl CPU {
         self.a = self.pop_byte(memory);
         12
       },
+      0xf2 => {
+        // LD A,(C)
+        self.a = memory.cycle_read(0xFF00 + self.c as u16);
+        8
+      },
       0xf3 => {
         // DI
         self.interrupts = false;
@@ -905,10 +1443,32 @@ impl CPU {
         self.push_short(memory, af);
         16
       },
+      0xf6 => {
+        // OR d8
+        let value = self.read_byte_immediate(memory);
+        or_a(&mut self.a, &mut self.f, value);
+        8
+      },
+      0xf7 => {
+        // RST 30H
+        self.restart(memory, 0x0030)
+      },
+      0xf8 => {
+        // LD HL,SP+r8
+        let offset = self.read_signed_byte_immediate(memory);
+        let value = self.offset_stack_pointer(offset);
+        self.set_hl(value);
+        12
+      },
+      0xf9 => {
+        // LD SP,HL
+        self.stack_pointer = self.hl();
+        8
+      },
       0xfa => {
         // LD A,(a16)
         let addr = self.read_short_immediate(memory);
-        self.a = memory.read_byte(addr);
+        self.a = memory.cycle_read(addr);
         16
       },
       0xfb => {
@@ -919,89 +1479,210 @@ impl CPU {
       0xfe => {
         // CP n
         let value = self.read_byte_immediate(memory);
-        self.f.set(ZERO, self.a == value);
-        self.f.insert(SUBTRACT);
-        self.f.set(HALF_CARRY, (self.a ^ value ^ self.a.wrapping_sub(value)) & 0x10 == 0x10);
-        self.f.set(CARRY, self.a > value);
+        cp_a(self.a, &mut self.f, value);
         8
       },
       0xff => {
-        // RST 38
-        let pc = self.program_counter;
-        self.push_short(memory, pc);
-        self.program_counter = 0x0038;
-        16
+        // RST 38H
+        self.restart(memory, 0x0038)
       },
       _ => {
-        unimplemented!()
+        // 0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc and 0xfd
+        // don't exist. Real hardware hangs until it is power cycled.
+        warn!("Illegal opcode {:02x} at address {:04x}, locking up", opcode, self.program_counter.wrapping_sub(1));
+        self.locked = true;
+        4
       }
     }
   }
 
-  fn cb(&mut self, opcode: u8) -> i64 {
-    println!("cb {:02x}", opcode);
-    match opcode {
-      0x37 => {
-        // SWAP A
-        self.f.set(ZERO, self.a == 0);
+  // Returns the cycles for the whole instruction, including the 0xCB prefix
+  fn cb(&mut self, memory: &mut Memory, opcode: u8) -> i64 {
+    trace!("cb {:02x}", opcode);
+    // The low three bits pick the operand, the next three pick the operation
+    // (or the bit number for BIT/RES/SET)
+    let operand = opcode & 0x07;
+    let bit = (opcode >> 3) & 0x07;
+    let value = self.read_operand(memory, operand);
+    let result = match opcode >> 6 {
+      0 => {
+        match bit {
+          0 => rlc(value, &mut self.f), // RLC
+          1 => rrc(value, &mut self.f), // RRC
+          2 => rl(value, &mut self.f),  // RL
+          3 => rr(value, &mut self.f),  // RR
+          4 => sla(value, &mut self.f), // SLA
+          5 => sra(value, &mut self.f), // SRA
+          6 => swap(value, &mut self.f), // SWAP
+          _ => srl(value, &mut self.f)  // SRL
+        }
+      },
+      1 => {
+        // BIT b,n
+        self.f.set(ZERO, value & (1 << bit) == 0);
         self.f.remove(SUBTRACT);
-        self.f.remove(HALF_CARRY);
-        self.f.remove(CARRY);
-        8
+        self.f.insert(HALF_CARRY);
+        // BIT only reads, so (HL) skips the write cycle
+        return if operand == 6 { 12 } else { 8 };
+      },
+      2 => {
+        // RES b,n
+        value & !(1 << bit)
       },
       _ => {
-        unimplemented!()
+        // SET b,n
+        value | (1 << bit)
       }
+    };
+    self.write_operand(memory, operand, result);
+    if operand == 6 { 16 } else { 8 }
+  }
+
+  // Operand encoding shared by the CB prefixed instructions:
+  // B, C, D, E, H, L, (HL), A
+  fn read_operand(&self, memory: &mut Memory, operand: u8) -> u8 {
+    match operand {
+      0 => self.b,
+      1 => self.c,
+      2 => self.d,
+      3 => self.e,
+      4 => self.h,
+      5 => self.l,
+      6 => memory.cycle_read(self.hl()),
+      _ => self.a
+    }
+  }
+
+  fn write_operand(&mut self, memory: &mut Memory, operand: u8, value: u8) {
+    match operand {
+      0 => self.b = value,
+      1 => self.c = value,
+      2 => self.d = value,
+      3 => self.e = value,
+      4 => self.h = value,
+      5 => self.l = value,
+      6 => memory.cycle_write(self.hl(), value),
+      _ => self.a = value
     }
   }
 
   fn relative_jump(&mut self, rel_target: i8) {
-    if rel_target < 0 {
-      self.program_counter -= -rel_target as u16;
+    self.program_counter = self.program_counter.wrapping_add(rel_target as i16 as u16);
+  }
+
+  fn relative_jump_if(&mut self, memory: &mut Memory, condition: bool) -> i64 {
+    let rel_target = self.read_signed_byte_immediate(memory);
+    if condition {
+      self.relative_jump(rel_target);
+      12
+    } else {
+      8
+    }
+  }
+
+  fn jump_if(&mut self, memory: &mut Memory, condition: bool) -> i64 {
+    let target = self.read_short_immediate(memory);
+    if condition {
+      self.program_counter = target;
+      16
+    } else {
+      12
+    }
+  }
+
+  fn call_if(&mut self, memory: &mut Memory, condition: bool) -> i64 {
+    let target = self.read_short_immediate(memory);
+    if condition {
+      let pc = self.program_counter;
+      self.push_short(memory, pc);
+      self.program_counter = target;
+      24
+    } else {
+      12
+    }
+  }
+
+  fn return_if(&mut self, memory: &mut Memory, condition: bool) -> i64 {
+    // Checking the condition takes an M-cycle of its own
+    memory.cycle_idle();
+    if condition {
+      let dest = self.pop_short(memory);
+      self.program_counter = dest;
+      20
     } else {
-      self.program_counter += rel_target as u16;
+      8
     }
   }
 
+  fn restart(&mut self, memory: &mut Memory, target: u16) -> i64 {
+    let pc = self.program_counter;
+    self.push_short(memory, pc);
+    self.program_counter = target;
+    16
+  }
+
+  fn add_hl(&mut self, value: u16) {
+    let orig = self.hl();
+    let res = orig.wrapping_add(value);
+    self.set_hl(res);
+    self.f.remove(SUBTRACT);
+    self.f.set(HALF_CARRY, (orig & 0x0fff) + (value & 0x0fff) > 0x0fff);
+    self.f.set(CARRY, res < orig);
+  }
+
+  // Shared by ADD SP,r8 and LD HL,SP+r8. The flags come from the unsigned
+  // addition of the low byte, regardless of the sign of the offset.
+  fn offset_stack_pointer(&mut self, offset: i8) -> u16 {
+    let sp = self.stack_pointer;
+    let value = offset as i16 as u16;
+    self.f.remove(ZERO);
+    self.f.remove(SUBTRACT);
+    self.f.set(HALF_CARRY, (sp & 0x000f) + (value & 0x000f) > 0x000f);
+    self.f.set(CARRY, (sp & 0x00ff) + (value & 0x00ff) > 0x00ff);
+    sp.wrapping_add(value)
+  }
+
+  // Every push of a 16-bit value spends an M-cycle decrementing SP before
+  // anything is written
   fn push_short(&mut self, memory: &mut Memory, value: u16) {
-    println!("pushing {:x} onto stack", value);
+    trace!("pushing {:x} onto stack", value);
+    memory.cycle_idle();
     self.push_byte(memory, value.hi());
     self.push_byte(memory, value.lo());
   }
 
   fn push_byte(&mut self, memory: &mut Memory, value: u8) {
     self.stack_pointer = self.stack_pointer.wrapping_sub(1);
-    memory.write_byte(self.stack_pointer, value);
+    memory.cycle_write(self.stack_pointer, value);
   }
 
-  fn pop_short(&mut self, memory: &Memory) -> u16 {
+  fn pop_short(&mut self, memory: &mut Memory) -> u16 {
     let lo = self.pop_byte(memory) as u16;
     let t = (self.pop_byte(memory) as u16) << 8 | lo;
-    println!("popping {:x} off stack", t);
+    trace!("popping {:x} off stack", t);
     t
   }
 
-  fn pop_byte(&mut self, memory: &Memory) -> u8 {
-    let x = memory.read_byte(self.stack_pointer);
+  fn pop_byte(&mut self, memory: &mut Memory) -> u8 {
+    let x = memory.cycle_read(self.stack_pointer);
     self.stack_pointer = self.stack_pointer.wrapping_add(1);
     x
   }
 
-  fn read_short_immediate(&mut self, memory: &Memory) -> u16 {
-    let value = memory.read_short(self.program_counter);
-    self.program_counter += 2;
-    value
+  fn read_short_immediate(&mut self, memory: &mut Memory) -> u16 {
+    let lo = self.read_byte_immediate(memory) as u16;
+    (self.read_byte_immediate(memory) as u16) << 8 | lo
   }
 
-  fn read_byte_immediate(&mut self, memory: &Memory) -> u8 {
-    let value = memory.read_byte(self.program_counter);
-    self.program_counter += 1;
+  fn read_byte_immediate(&mut self, memory: &mut Memory) -> u8 {
+    let value = memory.cycle_read(self.program_counter);
+    self.program_counter = self.program_counter.wrapping_add(1);
     value
   }
 
-  fn read_signed_byte_immediate(&mut self, memory: &Memory) -> i8 {
-    let value = memory.read_signed_byte(self.program_counter);
-    self.program_counter += 1;
+  fn read_signed_byte_immediate(&mut self, memory: &mut Memory) -> i8 {
+    let value = memory.cycle_read(self.program_counter) as i8;
+    self.program_counter = self.program_counter.wrapping_add(1);
     value
   }
 
@@ -1009,6 +1690,11 @@ impl CPU {
     (self.h as u16) << 8 | self.l as u16
   }
 
+  fn set_hl(&mut self, value: u16) {
+    self.h = value.hi();
+    self.l = value.lo();
+  }
+
   fn af(&self) -> u16 {
     (self.a as u16) << 8 | self.f.bits as u16
   }
@@ -1022,13 +1708,17 @@ impl CPU {
   }
 }
 
+fn interrupt_pending(memory: &mut Memory) -> bool {
+  memory.read_byte(0xffff) & memory.read_byte(0xff0f) & 0b00011111 != 0
+}
+
 fn inc_r8(register: &mut u8, flags: &mut Flags) -> i64 {
   // INC 8-bit register
   let orig = *register;
   *register = (*register).wrapping_add(1);
   (*flags).set(ZERO, *register == 0);
   (*flags).remove(SUBTRACT);
-  (*flags).set(HALF_CARRY, (orig ^ 1 ^ *register & 0x10) == 0x10);
+  (*flags).set(HALF_CARRY, (orig & 0x0f) == 0x0f);
   4
 }
 
@@ -1038,10 +1728,85 @@ fn dec_r8(register: &mut u8, flags: &mut Flags) -> i64 {
   *register = (*register).wrapping_sub(1);
   (*flags).set(ZERO, (*register) == 0);
   (*flags).insert(SUBTRACT);
-  (*flags).set(HALF_CARRY, (orig ^ !1 ^ *register & 0x10) == 0x10);
+  (*flags).set(HALF_CARRY, (orig & 0x0f) == 0x00);
   4
 }
 
+fn add_a(a: &mut u8, flags: &mut Flags, value: u8) {
+  // ADD A,n
+  let orig = *a;
+  *a = orig.wrapping_add(value);
+  flags.set(ZERO, *a == 0);
+  flags.remove(SUBTRACT);
+  flags.set(HALF_CARRY, (orig & 0x0f) + (value & 0x0f) > 0x0f);
+  flags.set(CARRY, (orig as u16) + (value as u16) > 0xff);
+}
+
+fn adc_a(a: &mut u8, flags: &mut Flags, value: u8) {
+  // ADC A,n
+  let orig = *a;
+  let carry = (flags.bits & CARRY.bits) >> 4; // 0 or 1
+  *a = orig.wrapping_add(value).wrapping_add(carry);
+  flags.set(ZERO, *a == 0);
+  flags.remove(SUBTRACT);
+  flags.set(HALF_CARRY, (orig & 0x0f) + (value & 0x0f) + carry > 0x0f);
+  flags.set(CARRY, (orig as u16) + (value as u16) + (carry as u16) > 0xff);
+}
+
+fn sub_a(a: &mut u8, flags: &mut Flags, value: u8) {
+  // SUB n
+  let orig = *a;
+  *a = orig.wrapping_sub(value);
+  flags.set(ZERO, *a == 0);
+  flags.insert(SUBTRACT);
+  flags.set(HALF_CARRY, (orig & 0x0f) < (value & 0x0f));
+  flags.set(CARRY, orig < value);
+}
+
+fn sbc_a(a: &mut u8, flags: &mut Flags, value: u8) {
+  // SBC A,n
+  let orig = *a;
+  let carry = (flags.bits & CARRY.bits) >> 4; // 0 or 1
+  *a = orig.wrapping_sub(value).wrapping_sub(carry);
+  flags.set(ZERO, *a == 0);
+  flags.insert(SUBTRACT);
+  flags.set(HALF_CARRY, (orig & 0x0f) < (value & 0x0f) + carry);
+  flags.set(CARRY, (orig as u16) < (value as u16) + (carry as u16));
+}
+
+fn and_a(a: &mut u8, flags: &mut Flags, value: u8) {
+  // AND n
+  *a &= value;
+  flags.set(ZERO, *a == 0);
+  flags.remove(SUBTRACT);
+  flags.insert(HALF_CARRY);
+  flags.remove(CARRY);
+}
+
+fn xor_a(a: &mut u8, flags: &mut Flags, value: u8) {
+  // XOR n
+  *a ^= value;
+  flags.set(ZERO, *a == 0);
+  flags.remove(SUBTRACT);
+  flags.remove(HALF_CARRY);
+  flags.remove(CARRY);
+}
+
+fn or_a(a: &mut u8, flags: &mut Flags, value: u8) {
+  // OR n
+  *a |= value;
+  flags.set(ZERO, *a == 0);
+  flags.remove(SUBTRACT);
+  flags.remove(HALF_CARRY);
+  flags.remove(CARRY);
+}
+
+fn cp_a(a: u8, flags: &mut Flags, value: u8) {
+  // CP n, a SUB that throws away the result
+  let mut scratch = a;
+  sub_a(&mut scratch, flags, value);
+}
+
 fn inc_double_r8(hi_reg: &mut u8, lo_reg: &mut u8) -> i64 {
   // INC 16-bit register (formed by two 8-bit registers)
   let combined = (*hi_reg as u16) << 8 | *lo_reg as u16;
@@ -1059,3 +1824,147 @@ fn dec_double_r8(hi_reg: &mut u8, lo_reg: &mut u8) -> i64 {
   *lo_reg = val.lo();
   8
 }
+
+// The CB rotates and shifts all set Z from the result and clear N and H,
+// only the carry differs
+fn shift_flags(result: u8, carry: bool, flags: &mut Flags) -> u8 {
+  flags.set(ZERO, result == 0);
+  flags.remove(SUBTRACT);
+  flags.remove(HALF_CARRY);
+  flags.set(CARRY, carry);
+  result
+}
+
+fn rlc(value: u8, flags: &mut Flags) -> u8 {
+  shift_flags(value.rotate_left(1), value & 0x80 == 0x80, flags)
+}
+
+fn rrc(value: u8, flags: &mut Flags) -> u8 {
+  shift_flags(value.rotate_right(1), value & 0x01 == 0x01, flags)
+}
+
+fn rl(value: u8, flags: &mut Flags) -> u8 {
+  let old_carry = (flags.bits & CARRY.bits) >> 4; // 0 or 1
+  shift_flags(value << 1 | old_carry, value & 0x80 == 0x80, flags)
+}
+
+fn rr(value: u8, flags: &mut Flags) -> u8 {
+  let old_carry = (flags.bits & CARRY.bits) << 3; // 0 or 0x80
+  shift_flags(value >> 1 | old_carry, value & 0x01 == 0x01, flags)
+}
+
+fn sla(value: u8, flags: &mut Flags) -> u8 {
+  shift_flags(value << 1, value & 0x80 == 0x80, flags)
+}
+
+fn sra(value: u8, flags: &mut Flags) -> u8 {
+  // Arithmetic shift, bit 7 stays put
+  shift_flags(value >> 1 | (value & 0x80), value & 0x01 == 0x01, flags)
+}
+
+fn swap(value: u8, flags: &mut Flags) -> u8 {
+  shift_flags(value.rotate_left(4), false, flags)
+}
+
+fn srl(value: u8, flags: &mut Flags) -> u8 {
+  shift_flags(value >> 1, value & 0x01 == 0x01, flags)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mbc::RomOnly;
+
+  // A CPU about to run `program` from 0x100
+  fn setup(program: &[u8]) -> (CPU, Memory) {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+    (CPU::new(), Memory::new(Box::new(RomOnly::new(rom, 0, false))))
+  }
+
+  // TIMA at 0xFF, counting every 4 M-cycles, and about to overflow on the
+  // 4th M-cycle from now. The reload comes in the 5th.
+  fn overflow_soon(memory: &mut Memory) {
+    memory.write_byte(0xff04, 0);
+    memory.write_byte(0xff05, 0xff);
+    memory.write_byte(0xff06, 0x42);
+    memory.write_byte(0xff07, 0b00000101);
+  }
+
+  #[test]
+  fn tima_write_while_reload_pending_cancels_it() {
+    // NOP, LDH (05),A. The write lands in the 4th M-cycle, TIMA reads 0.
+    let (mut cpu, mut memory) = setup(&[0x00, 0xe0, 0x05]);
+    overflow_soon(&mut memory);
+    cpu.step(&mut memory);
+    cpu.step(&mut memory);
+    assert_eq!(memory.read_byte(0xff05), 0x01);
+    memory.tick(8);
+    assert_eq!(memory.read_byte(0xff05), 0x01);
+    assert_eq!(memory.read_byte(0xff0f) & TIMER.bits, 0);
+  }
+
+  #[test]
+  fn tima_write_while_reloading_is_ignored() {
+    // LD A,99, LDH (05),A. The write lands in the 5th M-cycle, with the reload.
+    let (mut cpu, mut memory) = setup(&[0x3e, 0x99, 0xe0, 0x05]);
+    overflow_soon(&mut memory);
+    cpu.step(&mut memory);
+    cpu.step(&mut memory);
+    assert_eq!(memory.read_byte(0xff05), 0x42);
+    assert_eq!(memory.read_byte(0xff0f) & TIMER.bits, TIMER.bits);
+  }
+
+  #[test]
+  fn tma_write_while_reloading_goes_to_tima() {
+    // LD A,99, LDH (06),A
+    let (mut cpu, mut memory) = setup(&[0x3e, 0x99, 0xe0, 0x06]);
+    overflow_soon(&mut memory);
+    cpu.step(&mut memory);
+    cpu.step(&mut memory);
+    assert_eq!(memory.read_byte(0xff05), 0x99);
+    assert_eq!(memory.read_byte(0xff06), 0x99);
+  }
+
+  #[test]
+  fn interrupt_cancelled_by_pushing_onto_ie() {
+    let (mut cpu, mut memory) = setup(&[]);
+    // The high byte of PC, 0x01, lands on IE and disables the timer
+    cpu.stack_pointer = 0x0000;
+    memory.write_byte(0xffff, TIMER.bits);
+    memory.write_byte(0xff0f, TIMER.bits);
+    assert_eq!(cpu.step(&mut memory), 20);
+    assert_eq!(cpu.program_counter, 0x0000);
+    assert_eq!(cpu.stack_pointer, 0xfffe);
+    assert_eq!(memory.read_byte(0xffff), 0x01);
+    assert_eq!(memory.read_byte(0xff0f) & 0b00011111, TIMER.bits);
+  }
+
+  #[test]
+  fn interrupt_requested_during_dispatch_wins() {
+    let (mut cpu, mut memory) = setup(&[]);
+    // TIMA overflows 2 M-cycles into the dispatch and reloads in the 3rd,
+    // while the high byte of PC is pushed
+    overflow_soon(&mut memory);
+    memory.tick(8);
+    memory.write_byte(0xffff, TIMER.bits | JOYPAD.bits);
+    memory.write_byte(0xff0f, JOYPAD.bits);
+    assert_eq!(cpu.step(&mut memory), 20);
+    assert_eq!(cpu.program_counter, 0x0050);
+    assert_eq!(memory.read_byte(0xff0f) & 0b00011111, JOYPAD.bits);
+  }
+
+  #[test]
+  fn interrupt_requested_during_low_push_survives() {
+    let (mut cpu, mut memory) = setup(&[]);
+    // TIMA reloads in the 4th M-cycle of the dispatch, while the low byte of
+    // PC is pushed, which is too late to win but shouldn't be lost
+    overflow_soon(&mut memory);
+    memory.tick(4);
+    memory.write_byte(0xffff, JOYPAD.bits);
+    memory.write_byte(0xff0f, JOYPAD.bits);
+    assert_eq!(cpu.step(&mut memory), 20);
+    assert_eq!(cpu.program_counter, 0x0060);
+    assert_eq!(memory.read_byte(0xff0f) & 0b00011111, TIMER.bits);
+  }
+}
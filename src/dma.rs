@@ -0,0 +1,65 @@
+use std::ops::Range;
+
+// 0xFF46
+//
+// Writing XX copies XX00 - XX9F into OAM, one byte per M-cycle, so 160
+// M-cycles (640 cycles) in all. The copy starts an M-cycle after the write.
+// While it runs the DMA owns the bus and the CPU can only reach the IO
+// registers and HRAM, which is why games wait for it from a routine in HRAM.
+
+const LENGTH: u16 = 0xa0;
+
+pub struct Dma {
+  register: u8,
+  // Bytes copied so far, LENGTH when idle
+  index: u16,
+  // M-cycles until the copy starts
+  delay: u8,
+  // Leftover cycles that didn't make a whole M-cycle
+  remainder: i64
+}
+
+impl Dma {
+  pub fn new() -> Dma {
+    Dma {
+      register: 0xff,
+      index: LENGTH,
+      delay: 0,
+      remainder: 0
+    }
+  }
+
+  pub fn read(&self) -> u8 {
+    self.register
+  }
+
+  // Writing while a copy is running starts over from the new source
+  pub fn write(&mut self, value: u8) {
+    self.register = value;
+    self.index = 0;
+    self.delay = 1;
+  }
+
+  pub fn active(&self) -> bool {
+    self.delay == 0 && self.index < LENGTH
+  }
+
+  pub fn source(&self) -> u16 {
+    (self.register as u16) << 8
+  }
+
+  // Returns the offsets into OAM that should be copied over these cycles
+  pub fn step(&mut self, cycles: i64) -> Range<u16> {
+    self.remainder += cycles;
+    let start = self.index;
+    while self.remainder >= 4 {
+      self.remainder -= 4;
+      if self.delay > 0 {
+        self.delay -= 1;
+      } else if self.index < LENGTH {
+        self.index += 1;
+      }
+    }
+    start..self.index
+  }
+}
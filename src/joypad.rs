@@ -0,0 +1,86 @@
+// 0xFF00 (P1/JOYP)
+//
+// The eight buttons sit on a 2x4 matrix. Writing 0 to bit 4 connects the
+// d-pad to the low nibble, writing 0 to bit 5 connects the action buttons.
+// A pressed button pulls its line low.
+
+const SELECT_DIRECTIONS: u8 = 0b00010000;
+const SELECT_ACTIONS: u8 = 0b00100000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+  Right,
+  Left,
+  Up,
+  Down,
+  A,
+  B,
+  Select,
+  Start
+}
+
+impl Button {
+  // Directions fill the low nibble of our pressed mask and actions the high
+  // nibble, each in the order they appear on their P1 lines
+  fn mask(&self) -> u8 {
+    match *self {
+      Button::Right => 0b00000001,
+      Button::Left => 0b00000010,
+      Button::Up => 0b00000100,
+      Button::Down => 0b00001000,
+      Button::A => 0b00010000,
+      Button::B => 0b00100000,
+      Button::Select => 0b01000000,
+      Button::Start => 0b10000000
+    }
+  }
+}
+
+pub struct Joypad {
+  // 1 = held down
+  pressed: u8,
+  // Bits 4 and 5 as last written
+  select: u8
+}
+
+impl Joypad {
+  pub fn new() -> Joypad {
+    Joypad {
+      pressed: 0,
+      select: SELECT_DIRECTIONS | SELECT_ACTIONS
+    }
+  }
+
+  pub fn read(&self) -> u8 {
+    0b11000000 | self.select | self.lines()
+  }
+
+  // These return true when the joypad interrupt should be requested, which
+  // happens whenever any of the four lines goes from high to low
+  pub fn write(&mut self, value: u8) -> bool {
+    let before = self.lines();
+    self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    before & !self.lines() != 0
+  }
+
+  pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+    let before = self.lines();
+    if pressed {
+      self.pressed |= button.mask();
+    } else {
+      self.pressed &= !button.mask();
+    }
+    before & !self.lines() != 0
+  }
+
+  fn lines(&self) -> u8 {
+    let mut lines = 0x0f;
+    if self.select & SELECT_DIRECTIONS == 0 {
+      lines &= !(self.pressed & 0x0f);
+    }
+    if self.select & SELECT_ACTIONS == 0 {
+      lines &= !(self.pressed >> 4);
+    }
+    lines
+  }
+}
@@ -6,21 +6,40 @@ extern crate glium;
 #[macro_use]
 extern crate log;
 extern crate log_panics;
+extern crate cpal;
+extern crate futures;
 #[macro_use]
 extern crate bitflags;
 extern crate image;
 
 use glium::DisplayBuild;
 use glium::Surface;
+use std::collections::HashSet;
+use std::thread;
 use std::time::{Duration, Instant};
 use conrod::{color, widget};
 use conrod::{Colorable, Positionable, Widget, Sizeable};
 
+mod apu;
+mod audio;
+mod blip;
+mod config;
 mod cpu;
+mod dma;
+mod joypad;
+mod mbc;
 mod memory;
+mod palette;
 mod rom;
+mod save;
+mod timer;
 mod util;
 mod ppu;
+mod recorder;
+
+// How far dynamic rate control may stretch the audio when syncing to video.
+// Half a percent is too little to hear as a change in pitch.
+const MAX_RATE_DELTA: f64 = 0.005;
 
 widget_ids!(
     struct Ids {
@@ -43,21 +62,59 @@ fn main() {
 
     let mut image_map = conrod::image::Map::<glium::texture::Texture2d>::new();
 
-    let mut memory = memory::Memory::new();
-    let mut cpu = cpu::CPU::new();
-    let mut ppu = ppu::PPU::new();
-    memory.memory[0xff44] = 0; // Start at scanline 0
-    memory.memory[0xff40] &= 0x80; // Flag LCD as on
     let rom_path = std::env::args().nth(1).expect("Gameboy ROM expected as argument");
+    let config = config::Config::load(config::CONFIG_PATH);
 
-    rom::load_rom(&mut memory, &rom_path).unwrap();
+    let (header, cartridge) = match rom::load_rom(&rom_path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            error!("{}: {}", rom_path, err);
+            std::process::exit(1);
+        }
+    };
+    info!("Loaded \"{}\" ({:?})", header.title, header.cart);
+
+    let mut memory = memory::Memory::new(cartridge);
+    memory.restrict_access = config.access_restrictions;
+    memory.apu.set_quality(config.audio_quality);
+    let audio = audio::Audio::open();
+    if let Some(ref audio) = audio {
+        memory.apu.set_sample_rate(audio.sample_rate);
+    }
+    let mut recording = None;
+    // To tell a real key press from auto-repeat
+    let mut held_keys = HashSet::new();
+    if header.cart.has_rumble() {
+        memory.cartridge.set_rumble_handler(Box::new(|on| {
+            info!("Rumble motor {}", if on { "on" } else { "off" });
+        }));
+    }
+
+    let mut save_file = save::SaveFile::new(&rom_path);
+    if header.cart.has_battery() {
+        if let Err(err) = save_file.load(&mut *memory.cartridge) {
+            error!("Could not load save: {}", err);
+        }
+    }
+
+    let mut cpu = cpu::CPU::new();
+    let mut ppu = ppu::PPU::new(config.palette);
+
+    // Audio sync needs somewhere to send the audio
+    let sync = match (config.sync, &audio) {
+        (config::SyncMode::Audio, &None) => config::SyncMode::Video,
+        (sync, _) => sync
+    };
+    info!("Syncing to {:?}", sync);
 
     let mut last_time = Instant::now();
-    let mut cpu_acc = 0;
-    let mut ppu_acc = 0;
+    // Cycles the wall clock says we owe
+    let mut cycle_budget = 0;
+    let mut nanos_remainder = 0;
+    // Cycles the CPU has run that the PPU hasn't caught up on yet
+    let mut ppu_cycles = 0;
     let game_screen = {
-        let draw_results = ppu.draw(&memory);
-        let texture = glium::texture::Texture2d::new(&display, draw_results.0).unwrap();
+        let texture = glium::texture::Texture2d::new(&display, ppu.draw()).unwrap();
         image_map.insert(texture)
     };
     'game: loop {
@@ -65,8 +122,6 @@ fn main() {
         if elapsed > Duration::from_millis(100) {
             elapsed = Duration::from_millis(100);
         };
-        cpu_acc += (elapsed.as_secs() as i64 * 1000000000) + elapsed.subsec_nanos() as i64;
-        ppu_acc += (elapsed.as_secs() as i64 * 1000000000) + elapsed.subsec_nanos() as i64;
         last_time = Instant::now();
 
         for event in display.poll_events() {
@@ -77,6 +132,26 @@ fn main() {
 
             match event {
                 glutin::Event::Closed => break 'game,
+                glutin::Event::KeyboardInput(state, _, Some(key)) => {
+                    if let Some(&button) = config.keys.get(&key) {
+                        memory.set_button(button, state == glutin::ElementState::Pressed);
+                    }
+                    // Holding a key down makes the OS repeat the press, but
+                    // hotkeys should only toggle once
+                    let first_press = match state {
+                        glutin::ElementState::Pressed => held_keys.insert(key),
+                        glutin::ElementState::Released => {
+                            held_keys.remove(&key);
+                            false
+                        }
+                    };
+                    if first_press {
+                        if let Some(&hotkey) = config.hotkeys.get(&key) {
+                            handle_hotkey(hotkey, &mut memory, &mut recording, &rom_path,
+                                          config.record_frames);
+                        }
+                    }
+                },
                 glutin::Event::Resized(width, height) => {
                     // Doo dad
                 }
@@ -85,27 +160,44 @@ fn main() {
         }
 
 
-        // TODO:
-        // I think it would be cool to emulate the next step and see how long that took
-        // then only follow through on it if we banked enough time
-        // right now we are going too fast
-        let mut did_something = true;
-        while did_something {
-            did_something = false;
-            if cpu_acc > 952 {
-                cpu_acc -= cpu.step(&mut memory) * 238;
-                did_something = true;
-            }
-            if ppu_acc > ppu.estimate_clock_cycles() as i64 * 238 {
-                ppu_acc -= ppu.step(&mut memory) * 238;
-                did_something = true;
+        // Either way the wall clock decides how much to run, so every frame
+        // gets an even share. Our clock and the sound card's never quite
+        // agree though, and the queue's fill level says which way they're
+        // off. Syncing to audio the emulator runs up to MAX_RATE_DELTA faster
+        // or slower to match the sound card. Syncing to video the emulator
+        // keeps time and the audio is resampled to match instead.
+        let mut speed = 1.0;
+        if let Some(ref audio) = audio {
+            let fill = audio.buffered() as f64 / audio.capacity() as f64;
+            let ratio = 1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill.min(1.0));
+            match sync {
+                config::SyncMode::Audio => speed = ratio,
+                config::SyncMode::Video => {
+                    // Changing it makes the resampler redo its setup, which
+                    // is wasted if the rate comes out the same
+                    let sample_rate = (audio.sample_rate as f64 * ratio) as u32;
+                    if sample_rate != memory.apu.sample_rate() {
+                        memory.apu.set_sample_rate(sample_rate);
+                    }
+                }
             }
         }
-        while ppu_acc > ppu.estimate_clock_cycles() * 238 {
-            
+        let nanos = elapsed.as_secs() as i64 * 1000000000 + elapsed.subsec_nanos() as i64;
+        let owed = (nanos as f64 * speed) as i64 * apu::CLOCK_RATE + nanos_remainder;
+        cycle_budget += owed / 1000000000;
+        nanos_remainder = owed % 1000000000;
+        let ran = emulate(&mut cpu, &mut memory, &mut ppu, &mut ppu_cycles, cycle_budget);
+        cycle_budget -= ran;
+        if cpu.stopped() {
+            // Nothing runs until a button press, so don't bank the time
+            cycle_budget = 0;
         }
-        let draw_results = ppu.draw(&memory);
-        let texture = glium::texture::Texture2d::new(&display, draw_results.0).unwrap();
+        play_samples(&mut memory, audio.as_ref(), &mut recording);
+        if ran == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        save_file.update(&mut *memory.cartridge);
+        let texture = glium::texture::Texture2d::new(&display, ppu.draw()).unwrap();
         let _ = image_map.replace(game_screen, texture);
         ui.needs_redraw();
 
@@ -119,7 +211,10 @@ fn main() {
             .label_color(color::WHITE)
             .set(ids.tabs, ui);
 
-            widget::Image::new(game_screen).w_h(256.0f64, 256.0f64).middle_of(ids.tab_game).set(ids.game_screen, ui);
+            widget::Image::new(game_screen)
+            .w_h(ppu::SCREEN_WIDTH as f64 * 3.0, ppu::SCREEN_HEIGHT as f64 * 3.0)
+            .middle_of(ids.tab_game)
+            .set(ids.game_screen, ui);
         }
 
         // Render the `Ui` and then display it on the screen.
@@ -131,4 +226,98 @@ fn main() {
             target.finish().unwrap();
         }
     }
+
+    if let Err(err) = save_file.flush(&*memory.cartridge) {
+        error!("Could not write save: {}", err);
+    }
+    stop_recording(&mut memory, &mut recording);
+}
+
+fn handle_hotkey(hotkey: config::Hotkey, memory: &mut memory::Memory,
+                 recording: &mut Option<recorder::Recorder>, rom_path: &str, frames: u32) {
+    match hotkey {
+        config::Hotkey::Mute(channel) => {
+            let muted = memory.apu.toggle_mute(channel);
+            info!("{} {}", apu::CHANNEL_NAMES[channel], if muted { "muted" } else { "unmuted" });
+        },
+        config::Hotkey::Solo(channel) => {
+            let soloed = memory.apu.toggle_solo(channel);
+            info!("{} {}", apu::CHANNEL_NAMES[channel], if soloed { "soloed" } else { "unsoloed" });
+        },
+        config::Hotkey::RecordMix | config::Hotkey::RecordChannels => {
+            // Pressing either one again stops early
+            if recording.is_some() {
+                stop_recording(memory, recording);
+                return;
+            }
+            let channels = hotkey == config::Hotkey::RecordChannels;
+            let recorder = if channels {
+                recorder::Recorder::channels(rom_path, frames)
+            } else {
+                recorder::Recorder::mix(rom_path, frames)
+            };
+            match recorder {
+                Ok(recorder) => {
+                    memory.apu.start_recording(recorder::SAMPLE_RATE, channels);
+                    *recording = Some(recorder);
+                },
+                Err(err) => error!("Could not start recording: {}", err)
+            }
+        }
+    }
+}
+
+// Sends everything the APU has made since last time to the sound card and
+// the recording, if there is one
+fn play_samples(memory: &mut memory::Memory, audio: Option<&audio::Audio>,
+                recording: &mut Option<recorder::Recorder>) {
+    let samples = memory.apu.take_samples();
+    if let Some(audio) = audio {
+        audio.queue_samples(&samples);
+    }
+    let finished = match *recording {
+        Some(ref mut recorder) => {
+            match recorder.record(&memory.apu.take_recorded_samples()) {
+                Ok(()) => recorder.done(),
+                Err(err) => {
+                    error!("Could not write recording: {}", err);
+                    true
+                }
+            }
+        },
+        None => false
+    };
+    if finished {
+        stop_recording(memory, recording);
+    }
+}
+
+fn stop_recording(memory: &mut memory::Memory, recording: &mut Option<recorder::Recorder>) {
+    if let Some(recorder) = recording.take() {
+        if let Err(err) = recorder.finish() {
+            error!("Could not finish recording: {}", err);
+        }
+        memory.apu.stop_recording();
+    }
+}
+
+// Runs the CPU and everything clocked along with it for at least `cycles`,
+// returning how many actually ran. Stops early if the CPU enters STOP.
+fn emulate(cpu: &mut cpu::CPU, memory: &mut memory::Memory, ppu: &mut ppu::PPU,
+           ppu_cycles: &mut i64, cycles: i64) -> i64 {
+    let mut ran = 0;
+    while ran < cycles {
+        // The CPU clocks the rest of the bus itself, as it goes through
+        // each instruction
+        let step = cpu.step(memory);
+        *ppu_cycles += step;
+        while *ppu_cycles >= ppu.estimate_clock_cycles() {
+            *ppu_cycles -= ppu.step(memory);
+        }
+        ran += step;
+        if cpu.stopped() {
+            break;
+        }
+    }
+    ran
 }
@@ -0,0 +1,129 @@
+use mbc::{BatteryRam, Mbc, ROM_BANK_SIZE, RAM_BANK_SIZE};
+
+pub struct Mbc1 {
+  rom: Vec<u8>,
+  ram: BatteryRam,
+  // 0x0000 - 0x1FFF, 0x0A in the low nibble enables RAM
+  ram_enabled: bool,
+  // 0x2000 - 0x3FFF, 5 bits. Can never be 0, writing 0 selects 1.
+  bank1: u8,
+  // 0x4000 - 0x5FFF, 2 bits. Either the RAM bank or the upper ROM bank bits.
+  bank2: u8,
+  // 0x6000 - 0x7FFF. When set, bank2 also applies to 0x0000 - 0x3FFF and RAM.
+  advanced_banking: bool,
+  // MBC1M multicarts only wire up 4 bits of bank1, so bank2 starts at bit 4
+  multicart: bool
+}
+
+impl Mbc1 {
+  pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool) -> Mbc1 {
+    let multicart = is_multicart(&rom);
+    if multicart {
+      info!("Detected an MBC1M multicart");
+    }
+    Mbc1 {
+      rom: rom,
+      ram: BatteryRam::new(ram_size, battery),
+      ram_enabled: false,
+      bank1: 1,
+      bank2: 0,
+      advanced_banking: false,
+      multicart: multicart
+    }
+  }
+
+  fn bank2_shift(&self) -> u8 {
+    if self.multicart { 4 } else { 5 }
+  }
+
+  fn rom_bank_count(&self) -> usize {
+    self.rom.len() / ROM_BANK_SIZE
+  }
+
+  // 0x0000 - 0x3FFF is normally bank 0, but in advanced banking mode the
+  // upper bits still come from bank2. This is how 1 MiB+ carts reach 0x20/0x40/0x60.
+  fn low_rom_bank(&self) -> usize {
+    if self.advanced_banking {
+      ((self.bank2 as usize) << self.bank2_shift()) % self.rom_bank_count()
+    } else {
+      0
+    }
+  }
+
+  // The 0 -> 1 adjustment only looks at bank1, so 0x20/0x40/0x60 map to
+  // 0x21/0x41/0x61 here
+  fn high_rom_bank(&self) -> usize {
+    let bank1 = if self.multicart { self.bank1 & 0x0f } else { self.bank1 };
+    ((self.bank2 as usize) << self.bank2_shift() | bank1 as usize) % self.rom_bank_count()
+  }
+
+  fn ram_offset(&self, address: u16) -> usize {
+    let bank = if self.advanced_banking { self.bank2 as usize } else { 0 };
+    // Carts with 2 KiB or 8 KiB of RAM just ignore the extra address lines
+    (bank * RAM_BANK_SIZE + (address - 0xA000) as usize) % self.ram.len()
+  }
+}
+
+impl Mbc for Mbc1 {
+  fn read_rom(&self, address: u16) -> u8 {
+    if address < 0x4000 {
+      self.rom[self.low_rom_bank() * ROM_BANK_SIZE + address as usize]
+    } else {
+      self.rom[self.high_rom_bank() * ROM_BANK_SIZE + (address - 0x4000) as usize]
+    }
+  }
+
+  fn write_rom(&mut self, address: u16, value: u8) {
+    if address < 0x2000 {
+      self.ram_enabled = value & 0x0f == 0x0a;
+    } else if address < 0x4000 {
+      self.bank1 = value & 0x1f;
+      if self.bank1 == 0 {
+        self.bank1 = 1;
+      }
+    } else if address < 0x6000 {
+      self.bank2 = value & 0x03;
+    } else {
+      self.advanced_banking = value & 0x01 == 0x01;
+    }
+  }
+
+  fn read_ram(&self, address: u16) -> u8 {
+    if !self.ram_enabled || self.ram.is_empty() {
+      return 0xff;
+    }
+    self.ram.read(self.ram_offset(address))
+  }
+
+  fn write_ram(&mut self, address: u16, value: u8) {
+    if !self.ram_enabled || self.ram.is_empty() {
+      return;
+    }
+    let offset = self.ram_offset(address);
+    self.ram.write(offset, value);
+  }
+
+  fn save_data(&self) -> Option<Vec<u8>> {
+    self.ram.save_data()
+  }
+
+  fn load_save_data(&mut self, data: &[u8]) {
+    self.ram.load_save_data(data);
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    self.ram.take_dirty()
+  }
+}
+
+// MBC1M carts are 1 MiB collections of 256 KiB games, each of which starts
+// with its own header. Look for a second copy of the Nintendo logo where the
+// second game would begin.
+fn is_multicart(rom: &[u8]) -> bool {
+  if rom.len() != 64 * ROM_BANK_SIZE {
+    return false;
+  }
+  let logo = &rom[0x0104..0x0134];
+  let second = 0x10 * ROM_BANK_SIZE;
+  &rom[second + 0x0104..second + 0x0134] == logo
+}
@@ -0,0 +1,83 @@
+use mbc::{BatteryRam, Mbc, ROM_BANK_SIZE};
+
+// The RAM is built into the controller, 512 half-bytes
+const RAM_SIZE: usize = 512;
+
+pub struct Mbc2 {
+  rom: Vec<u8>,
+  // Only the low nibble of each byte exists
+  ram: BatteryRam,
+  ram_enabled: bool,
+  // 4 bits. Writing 0 selects 1.
+  rom_bank: u8
+}
+
+impl Mbc2 {
+  pub fn new(rom: Vec<u8>, battery: bool) -> Mbc2 {
+    Mbc2 {
+      rom: rom,
+      ram: BatteryRam::new(RAM_SIZE, battery),
+      ram_enabled: false,
+      rom_bank: 1
+    }
+  }
+
+  fn rom_bank_count(&self) -> usize {
+    self.rom.len() / ROM_BANK_SIZE
+  }
+}
+
+impl Mbc for Mbc2 {
+  fn read_rom(&self, address: u16) -> u8 {
+    if address < 0x4000 {
+      self.rom[address as usize]
+    } else {
+      let bank = self.rom_bank as usize % self.rom_bank_count();
+      self.rom[bank * ROM_BANK_SIZE + (address - 0x4000) as usize]
+    }
+  }
+
+  fn write_rom(&mut self, address: u16, value: u8) {
+    // Both registers share 0x0000 - 0x3FFF, address bit 8 picks which one
+    if address >= 0x4000 {
+      return;
+    }
+    if address & 0x0100 == 0 {
+      self.ram_enabled = value & 0x0f == 0x0a;
+    } else {
+      self.rom_bank = value & 0x0f;
+      if self.rom_bank == 0 {
+        self.rom_bank = 1;
+      }
+    }
+  }
+
+  fn read_ram(&self, address: u16) -> u8 {
+    if !self.ram_enabled {
+      return 0xff;
+    }
+    // Only 9 address lines, so the 512 bytes echo all the way up to 0xBFFF.
+    // The upper nibble isn't connected and reads back as 1s, whatever a
+    // save file put there.
+    0xf0 | self.ram.read((address as usize) & (RAM_SIZE - 1))
+  }
+
+  fn write_ram(&mut self, address: u16, value: u8) {
+    if !self.ram_enabled {
+      return;
+    }
+    self.ram.write((address as usize) & (RAM_SIZE - 1), value & 0x0f);
+  }
+
+  fn save_data(&self) -> Option<Vec<u8>> {
+    self.ram.save_data()
+  }
+
+  fn load_save_data(&mut self, data: &[u8]) {
+    self.ram.load_save_data(data);
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    self.ram.take_dirty()
+  }
+}
@@ -0,0 +1,118 @@
+use mbc::{BatteryRam, Mbc, ROM_BANK_SIZE, RAM_BANK_SIZE};
+use mbc::rtc::Rtc;
+
+pub struct Mbc3 {
+  rom: Vec<u8>,
+  ram: BatteryRam,
+  // 0x0000 - 0x1FFF, 0x0A enables both RAM and the RTC registers
+  ram_enabled: bool,
+  // 0x2000 - 0x3FFF, 7 bits. Writing 0 selects 1.
+  rom_bank: u8,
+  // 0x4000 - 0x5FFF, 0x00 - 0x03 picks a RAM bank, 0x08 - 0x0C an RTC register
+  select: u8,
+  rtc: Option<Rtc>
+}
+
+impl Mbc3 {
+  pub fn new(rom: Vec<u8>, ram_size: usize, timer: bool, battery: bool) -> Mbc3 {
+    Mbc3 {
+      rom: rom,
+      ram: BatteryRam::new(ram_size, battery),
+      ram_enabled: false,
+      rom_bank: 1,
+      select: 0,
+      rtc: if timer { Some(Rtc::new()) } else { None }
+    }
+  }
+
+  fn rom_bank_count(&self) -> usize {
+    self.rom.len() / ROM_BANK_SIZE
+  }
+
+  fn ram_offset(&self, address: u16) -> usize {
+    (self.select as usize * RAM_BANK_SIZE + (address - 0xA000) as usize) % self.ram.len()
+  }
+}
+
+impl Mbc for Mbc3 {
+  fn read_rom(&self, address: u16) -> u8 {
+    if address < 0x4000 {
+      self.rom[address as usize]
+    } else {
+      let bank = self.rom_bank as usize % self.rom_bank_count();
+      self.rom[bank * ROM_BANK_SIZE + (address - 0x4000) as usize]
+    }
+  }
+
+  fn write_rom(&mut self, address: u16, value: u8) {
+    if address < 0x2000 {
+      self.ram_enabled = value & 0x0f == 0x0a;
+    } else if address < 0x4000 {
+      self.rom_bank = value & 0x7f;
+      if self.rom_bank == 0 {
+        self.rom_bank = 1;
+      }
+    } else if address < 0x6000 {
+      self.select = value & 0x0f;
+    } else if let Some(ref mut rtc) = self.rtc {
+      rtc.write_latch(value);
+    }
+  }
+
+  fn read_ram(&self, address: u16) -> u8 {
+    if !self.ram_enabled {
+      return 0xff;
+    }
+    if Rtc::is_register(self.select) {
+      match self.rtc {
+        Some(ref rtc) => rtc.read(self.select),
+        None => 0xff
+      }
+    } else if self.select < 0x04 && !self.ram.is_empty() {
+      self.ram.read(self.ram_offset(address))
+    } else {
+      0xff
+    }
+  }
+
+  fn write_ram(&mut self, address: u16, value: u8) {
+    if !self.ram_enabled {
+      return;
+    }
+    if Rtc::is_register(self.select) {
+      if let Some(ref mut rtc) = self.rtc {
+        rtc.write(self.select, value);
+        self.ram.mark_dirty();
+      }
+    } else if self.select < 0x04 && !self.ram.is_empty() {
+      let offset = self.ram_offset(address);
+      self.ram.write(offset, value);
+    }
+  }
+
+  // The RTC registers follow the RAM in the .sav file
+  fn save_data(&self) -> Option<Vec<u8>> {
+    let mut data = match self.ram.save_data() {
+      Some(data) => data,
+      None => return None
+    };
+    if let Some(ref rtc) = self.rtc {
+      rtc.save(&mut data);
+    }
+    Some(data)
+  }
+
+  fn load_save_data(&mut self, data: &[u8]) {
+    self.ram.load_save_data(data);
+    let len = self.ram.len();
+    if let Some(ref mut rtc) = self.rtc {
+      if data.len() > len && !rtc.load(&data[len..]) {
+        warn!("Ignoring RTC footer of unknown size {}", data.len() - len);
+      }
+    }
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    self.ram.take_dirty()
+  }
+}
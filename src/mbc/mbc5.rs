@@ -0,0 +1,111 @@
+use mbc::{BatteryRam, Mbc, ROM_BANK_SIZE, RAM_BANK_SIZE};
+
+// Bit of the RAM bank register that drives the motor on rumble carts
+const RUMBLE_MOTOR: u8 = 0b00001000;
+
+pub struct Mbc5 {
+  rom: Vec<u8>,
+  ram: BatteryRam,
+  // 0x0000 - 0x1FFF
+  ram_enabled: bool,
+  // 0x2000 - 0x2FFF is the low 8 bits, 0x3000 - 0x3FFF the 9th. Unlike the
+  // older controllers bank 0 can be mapped to 0x4000 - 0x7FFF.
+  rom_bank: u16,
+  // 0x4000 - 0x5FFF, 4 bits, or 3 bits on rumble carts
+  ram_bank: u8,
+  rumble: bool,
+  motor_on: bool,
+  rumble_handler: Option<Box<FnMut(bool)>>
+}
+
+impl Mbc5 {
+  pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool, rumble: bool) -> Mbc5 {
+    Mbc5 {
+      rom: rom,
+      ram: BatteryRam::new(ram_size, battery),
+      ram_enabled: false,
+      rom_bank: 1,
+      ram_bank: 0,
+      rumble: rumble,
+      motor_on: false,
+      rumble_handler: None
+    }
+  }
+
+  fn rom_bank_count(&self) -> usize {
+    self.rom.len() / ROM_BANK_SIZE
+  }
+
+  fn ram_offset(&self, address: u16) -> usize {
+    (self.ram_bank as usize * RAM_BANK_SIZE + (address - 0xA000) as usize) % self.ram.len()
+  }
+
+  fn set_motor(&mut self, on: bool) {
+    if on == self.motor_on {
+      return;
+    }
+    self.motor_on = on;
+    if let Some(ref mut handler) = self.rumble_handler {
+      handler(on);
+    }
+  }
+}
+
+impl Mbc for Mbc5 {
+  fn read_rom(&self, address: u16) -> u8 {
+    if address < 0x4000 {
+      self.rom[address as usize]
+    } else {
+      let bank = self.rom_bank as usize % self.rom_bank_count();
+      self.rom[bank * ROM_BANK_SIZE + (address - 0x4000) as usize]
+    }
+  }
+
+  fn write_rom(&mut self, address: u16, value: u8) {
+    if address < 0x2000 {
+      self.ram_enabled = value & 0x0f == 0x0a;
+    } else if address < 0x3000 {
+      self.rom_bank = (self.rom_bank & 0x100) | value as u16;
+    } else if address < 0x4000 {
+      self.rom_bank = (self.rom_bank & 0xff) | ((value & 0x01) as u16) << 8;
+    } else if address < 0x6000 {
+      if self.rumble {
+        self.ram_bank = value & 0x07;
+        self.set_motor(value & RUMBLE_MOTOR == RUMBLE_MOTOR);
+      } else {
+        self.ram_bank = value & 0x0f;
+      }
+    }
+  }
+
+  fn read_ram(&self, address: u16) -> u8 {
+    if !self.ram_enabled || self.ram.is_empty() {
+      return 0xff;
+    }
+    self.ram.read(self.ram_offset(address))
+  }
+
+  fn write_ram(&mut self, address: u16, value: u8) {
+    if !self.ram_enabled || self.ram.is_empty() {
+      return;
+    }
+    let offset = self.ram_offset(address);
+    self.ram.write(offset, value);
+  }
+
+  fn save_data(&self) -> Option<Vec<u8>> {
+    self.ram.save_data()
+  }
+
+  fn load_save_data(&mut self, data: &[u8]) {
+    self.ram.load_save_data(data);
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    self.ram.take_dirty()
+  }
+
+  fn set_rumble_handler(&mut self, handler: Box<FnMut(bool)>) {
+    self.rumble_handler = Some(handler);
+  }
+}
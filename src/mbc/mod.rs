@@ -0,0 +1,144 @@
+use rom::{Cart, CartridgeHeader, RomError};
+
+mod rom_only;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod rtc;
+
+pub use self::rom_only::RomOnly;
+pub use self::mbc1::Mbc1;
+pub use self::mbc2::Mbc2;
+pub use self::mbc3::Mbc3;
+pub use self::mbc5::Mbc5;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+// Everything on the cartridge side of the bus. Memory hands off
+// 0x0000 - 0x7FFF and 0xA000 - 0xBFFF to one of these.
+pub trait Mbc {
+  // 0x0000 - 0x7FFF
+  fn read_rom(&self, address: u16) -> u8;
+  // Writes to ROM don't change ROM, they set the controller's registers
+  fn write_rom(&mut self, address: u16, value: u8);
+  // 0xA000 - 0xBFFF
+  fn read_ram(&self, address: u16) -> u8;
+  fn write_ram(&mut self, address: u16, value: u8);
+
+  // Everything the battery keeps alive, laid out the way it goes in the
+  // .sav file. None if the cart doesn't have a battery.
+  fn save_data(&self) -> Option<Vec<u8>> {
+    None
+  }
+
+  fn load_save_data(&mut self, _data: &[u8]) {
+  }
+
+  // True if save_data changed since the last call
+  fn take_dirty(&mut self) -> bool {
+    false
+  }
+
+  // Called with the new state whenever a rumble cart switches its motor
+  // on or off. Carts without a motor never call it.
+  fn set_rumble_handler(&mut self, _handler: Box<FnMut(bool)>) {
+  }
+}
+
+// External RAM on the cartridge. With a battery it outlives the power being
+// switched off, so it's what goes in the .sav file.
+pub struct BatteryRam {
+  data: Vec<u8>,
+  battery: bool,
+  // Written since the last take_dirty
+  dirty: bool
+}
+
+impl BatteryRam {
+  pub fn new(size: usize, battery: bool) -> BatteryRam {
+    BatteryRam {
+      data: vec![0; size],
+      battery: battery,
+      dirty: false
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.data.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.data.is_empty()
+  }
+
+  pub fn read(&self, offset: usize) -> u8 {
+    self.data[offset]
+  }
+
+  pub fn write(&mut self, offset: usize, value: u8) {
+    self.data[offset] = value;
+    self.dirty = true;
+  }
+
+  // For anything else the battery keeps, like MBC3's clock
+  pub fn mark_dirty(&mut self) {
+    self.dirty = true;
+  }
+
+  pub fn save_data(&self) -> Option<Vec<u8>> {
+    if self.battery {
+      Some(self.data.clone())
+    } else {
+      None
+    }
+  }
+
+  // Anything past the end of the RAM is left for the controller
+  pub fn load_save_data(&mut self, data: &[u8]) {
+    let len = if data.len() < self.data.len() { data.len() } else { self.data.len() };
+    self.data[..len].copy_from_slice(&data[..len]);
+  }
+
+  pub fn take_dirty(&mut self) -> bool {
+    let dirty = self.dirty;
+    self.dirty = false;
+    dirty
+  }
+}
+
+pub fn new(header: &CartridgeHeader, rom: Vec<u8>) -> Result<Box<Mbc>, RomError> {
+  let rom = pad_rom(rom);
+  match header.cart {
+    Cart::RomOnly | Cart::RomRam | Cart::RomRamBatt => {
+      Ok(Box::new(RomOnly::new(rom, header.ram_size, header.cart.has_battery())))
+    },
+    Cart::RomMBC1 | Cart::RomMBC1Ram | Cart::RomMBC1RamBatt => {
+      Ok(Box::new(Mbc1::new(rom, header.ram_size, header.cart.has_battery())))
+    },
+    Cart::RomMBC2 | Cart::RomMBC2Batt => {
+      Ok(Box::new(Mbc2::new(rom, header.cart.has_battery())))
+    },
+    Cart::RomMBC3 | Cart::RomMBC3Ram | Cart::RomMBC3RamBatt |
+    Cart::RomMBC3TimerBatt | Cart::RomMBC3TimerRamBatt => {
+      Ok(Box::new(Mbc3::new(rom, header.ram_size, header.cart.has_timer(), header.cart.has_battery())))
+    },
+    Cart::RomMBC5 | Cart::RomMBC5Ram | Cart::RomMBC5RamBatt |
+    Cart::RomMBC5Rumble | Cart::RomMBC5RumbleSram | Cart::RomMBC5RumbleSramBatt => {
+      Ok(Box::new(Mbc5::new(rom, header.ram_size, header.cart.has_battery(), header.cart.has_rumble())))
+    },
+    cart => Err(RomError::UnsupportedCart(cart))
+  }
+}
+
+// Makes sure there are at least two banks and that the last one is whole,
+// so the controllers can index by bank without bounds checks everywhere
+fn pad_rom(mut rom: Vec<u8>) -> Vec<u8> {
+  let mut len = (rom.len() + ROM_BANK_SIZE - 1) / ROM_BANK_SIZE * ROM_BANK_SIZE;
+  if len < ROM_BANK_SIZE * 2 {
+    len = ROM_BANK_SIZE * 2;
+  }
+  rom.resize(len, 0xff);
+  rom
+}
@@ -0,0 +1,53 @@
+use mbc::{BatteryRam, Mbc, RAM_BANK_SIZE};
+
+// 32 KiB of ROM and at most a single bank of RAM, nothing to switch
+pub struct RomOnly {
+  rom: Vec<u8>,
+  ram: BatteryRam
+}
+
+impl RomOnly {
+  pub fn new(rom: Vec<u8>, ram_size: usize, battery: bool) -> RomOnly {
+    RomOnly {
+      rom: rom,
+      ram: BatteryRam::new(if ram_size > RAM_BANK_SIZE { RAM_BANK_SIZE } else { ram_size }, battery)
+    }
+  }
+}
+
+impl Mbc for RomOnly {
+  fn read_rom(&self, address: u16) -> u8 {
+    self.rom[address as usize]
+  }
+
+  fn write_rom(&mut self, _address: u16, _value: u8) {
+  }
+
+  fn read_ram(&self, address: u16) -> u8 {
+    let offset = (address - 0xA000) as usize;
+    if offset < self.ram.len() {
+      self.ram.read(offset)
+    } else {
+      0xff
+    }
+  }
+
+  fn write_ram(&mut self, address: u16, value: u8) {
+    let offset = (address - 0xA000) as usize;
+    if offset < self.ram.len() {
+      self.ram.write(offset, value);
+    }
+  }
+
+  fn save_data(&self) -> Option<Vec<u8>> {
+    self.ram.save_data()
+  }
+
+  fn load_save_data(&mut self, data: &[u8]) {
+    self.ram.load_save_data(data);
+  }
+
+  fn take_dirty(&mut self) -> bool {
+    self.ram.take_dirty()
+  }
+}
@@ -0,0 +1,226 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Size of the footer other emulators (BGB, VBA-M, mGBA, ...) append to the
+// .sav file of MBC3 timer carts. Some older ones leave off the top half of
+// the timestamp, which makes it 44.
+const FOOTER_SIZE: usize = 48;
+const SHORT_FOOTER_SIZE: usize = 44;
+
+// Register numbers as selected by writing 0x08 - 0x0C to 0x4000 - 0x5FFF
+const SECONDS: u8 = 0x08;
+const MINUTES: u8 = 0x09;
+const HOURS: u8 = 0x0A;
+const DAYS_LOW: u8 = 0x0B;
+const DAYS_HIGH: u8 = 0x0C;
+
+// Bits of DAYS_HIGH
+const DAY_HIGH_BIT: u8 = 0b00000001;
+const HALT: u8 = 0b01000000;
+const DAY_CARRY: u8 = 0b10000000;
+
+#[derive(Clone)]
+pub struct Rtc {
+  seconds: u8,
+  minutes: u8,
+  hours: u8,
+  // 9 bits
+  days: u16,
+  halted: bool,
+  // Set when days overflows, stays set until the game clears it
+  day_carry: bool,
+  // What the game actually reads, a snapshot taken on latch
+  latched: [u8; 5],
+  // Writing 0x00 then 0x01 to 0x6000 - 0x7FFF latches
+  latch_armed: bool,
+  // Host time the registers were last brought up to date. Only whole
+  // seconds get applied so the fraction carries over to the next update.
+  last_update: SystemTime
+}
+
+impl Rtc {
+  pub fn new() -> Rtc {
+    Rtc {
+      seconds: 0,
+      minutes: 0,
+      hours: 0,
+      days: 0,
+      halted: false,
+      day_carry: false,
+      latched: [0; 5],
+      latch_armed: false,
+      last_update: SystemTime::now()
+    }
+  }
+
+  pub fn is_register(select: u8) -> bool {
+    select >= SECONDS && select <= DAYS_HIGH
+  }
+
+  pub fn read(&self, select: u8) -> u8 {
+    self.latched[(select - SECONDS) as usize]
+  }
+
+  pub fn write(&mut self, select: u8, value: u8) {
+    // Credit the time that passed under the old values first
+    self.update();
+    match select {
+      SECONDS => {
+        self.seconds = value & 0x3f;
+        // Writing seconds also resets the sub-second divider
+        self.last_update = SystemTime::now();
+      },
+      MINUTES => self.minutes = value & 0x3f,
+      HOURS => self.hours = value & 0x1f,
+      DAYS_LOW => self.days = (self.days & 0x100) | value as u16,
+      _ => {
+        self.days = (self.days & 0xff) | ((value & DAY_HIGH_BIT) as u16) << 8;
+        self.day_carry = value & DAY_CARRY == DAY_CARRY;
+        self.halted = value & HALT == HALT;
+      }
+    }
+  }
+
+  pub fn write_latch(&mut self, value: u8) {
+    if self.latch_armed && value == 0x01 {
+      self.update();
+      self.latched = self.registers();
+    }
+    self.latch_armed = value == 0x00;
+  }
+
+  // Brings the registers up to date with the host clock
+  pub fn update(&mut self) {
+    let now = SystemTime::now();
+    let elapsed = match now.duration_since(self.last_update) {
+      Ok(elapsed) => elapsed,
+      // The host clock went backwards, just start over from here
+      Err(_) => {
+        self.last_update = now;
+        return;
+      }
+    };
+    if self.halted {
+      self.last_update = now;
+    } else {
+      self.advance(elapsed.as_secs());
+      self.last_update += Duration::from_secs(elapsed.as_secs());
+    }
+  }
+
+  // Appends the 48 byte footer: the live registers, the latched registers
+  // (each as a little endian u32) and the unix time they were valid at
+  pub fn save(&self, data: &mut Vec<u8>) {
+    let mut rtc = self.clone();
+    rtc.update();
+    for register in rtc.registers().iter().chain(rtc.latched.iter()) {
+      write_u32(data, *register as u32);
+    }
+    let timestamp = match rtc.last_update.duration_since(UNIX_EPOCH) {
+      Ok(duration) => duration.as_secs(),
+      Err(_) => 0
+    };
+    write_u32(data, timestamp as u32);
+    write_u32(data, (timestamp >> 32) as u32);
+  }
+
+  // Loads a footer written by save. Returns false if the footer isn't a size
+  // we know about, in which case the clock is left alone.
+  pub fn load(&mut self, footer: &[u8]) -> bool {
+    if footer.len() != FOOTER_SIZE && footer.len() != SHORT_FOOTER_SIZE {
+      return false;
+    }
+    let mut registers = [0u8; 10];
+    for (i, register) in registers.iter_mut().enumerate() {
+      *register = read_u32(&footer[i * 4..]) as u8;
+    }
+    let mut timestamp = read_u32(&footer[40..]) as u64;
+    if footer.len() == FOOTER_SIZE {
+      timestamp |= (read_u32(&footer[44..]) as u64) << 32;
+    }
+
+    self.seconds = registers[0] & 0x3f;
+    self.minutes = registers[1] & 0x3f;
+    self.hours = registers[2] & 0x1f;
+    self.days = registers[3] as u16 | ((registers[4] & DAY_HIGH_BIT) as u16) << 8;
+    self.halted = registers[4] & HALT == HALT;
+    self.day_carry = registers[4] & DAY_CARRY == DAY_CARRY;
+    self.latched.copy_from_slice(&registers[5..10]);
+    // Catch up on however long the emulator was closed for
+    self.last_update = UNIX_EPOCH + Duration::from_secs(timestamp);
+    self.update();
+    true
+  }
+
+  fn registers(&self) -> [u8; 5] {
+    let mut days_high = (self.days >> 8) as u8 & DAY_HIGH_BIT;
+    if self.halted {
+      days_high |= HALT;
+    }
+    if self.day_carry {
+      days_high |= DAY_CARRY;
+    }
+    [self.seconds, self.minutes, self.hours, self.days as u8, days_high]
+  }
+
+  fn advance(&mut self, mut seconds: u64) {
+    // Games can write out of range values, which count up to the limit of
+    // their bits and wrap without carrying. Step through those one at a time.
+    while seconds > 0 && !self.in_range() {
+      self.tick();
+      seconds -= 1;
+    }
+    if seconds == 0 {
+      return;
+    }
+    let total = self.seconds as u64
+      + self.minutes as u64 * 60
+      + self.hours as u64 * 60 * 60
+      + self.days as u64 * 60 * 60 * 24
+      + seconds;
+    self.seconds = (total % 60) as u8;
+    self.minutes = (total / 60 % 60) as u8;
+    self.hours = (total / (60 * 60) % 24) as u8;
+    let days = total / (60 * 60 * 24);
+    if days > 0x1ff {
+      self.day_carry = true;
+    }
+    self.days = (days & 0x1ff) as u16;
+  }
+
+  fn in_range(&self) -> bool {
+    self.seconds < 60 && self.minutes < 60 && self.hours < 24
+  }
+
+  fn tick(&mut self) {
+    self.seconds = (self.seconds + 1) & 0x3f;
+    if self.seconds != 60 {
+      return;
+    }
+    self.seconds = 0;
+    self.minutes = (self.minutes + 1) & 0x3f;
+    if self.minutes != 60 {
+      return;
+    }
+    self.minutes = 0;
+    self.hours = (self.hours + 1) & 0x1f;
+    if self.hours != 24 {
+      return;
+    }
+    self.hours = 0;
+    self.days = (self.days + 1) & 0x1ff;
+    if self.days == 0 {
+      self.day_carry = true;
+    }
+  }
+}
+
+fn write_u32(data: &mut Vec<u8>, value: u32) {
+  data.push(value as u8);
+  data.push((value >> 8) as u8);
+  data.push((value >> 16) as u8);
+  data.push((value >> 24) as u8);
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+  data[0] as u32 | (data[1] as u32) << 8 | (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
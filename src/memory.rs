@@ -1,4 +1,9 @@
 use std;
+use apu::Apu;
+use dma::Dma;
+use joypad::{Button, Joypad};
+use mbc::Mbc;
+use timer::Timer;
 use util::LoHi;
 
 /* 
@@ -26,51 +31,235 @@ struct Memory {
 }
 */
 
+// Bit 4 of DIV in the full counter
+const FRAME_SEQUENCER_BIT: u16 = 1 << 12;
+
 pub struct Memory {
-  pub memory: Box<[u8; 65536]>
+  pub memory: Box<[u8; 65536]>,
+  // ROM and external RAM live on the cartridge
+  pub cartridge: Box<Mbc>,
+  // 0xFF00
+  pub joypad: Joypad,
+  // 0xFF04 - 0xFF07
+  pub timer: Timer,
+  // 0xFF10 - 0xFF3F
+  pub apu: Apu,
+  // 0xFF46
+  pub dma: Dma,
+  // Whether the CPU is locked out of VRAM and OAM while the PPU uses them
+  pub restrict_access: bool,
+  // The STAT bit (3 - 5) of the mode interrupt the PPU's current mode
+  // selects, or 0 if it can't raise one. Kept up to date by the PPU.
+  pub stat_mode_interrupt: u8,
+  // The STAT interrupt line, LCD_STAT is only requested when it rises
+  stat_line: bool,
+  // Cycles the CPU has already clocked through during this instruction
+  cpu_cycles: i64
 }
 
 impl Memory {
-  pub fn new() -> Memory {
-    Memory {
-      memory: Box::new(unsafe { std::mem::zeroed() })
+  pub fn new(cartridge: Box<Mbc>) -> Memory {
+    let mut memory = Memory {
+      memory: Box::new(unsafe { std::mem::zeroed() }),
+      cartridge: cartridge,
+      joypad: Joypad::new(),
+      timer: Timer::new(),
+      apu: Apu::new(),
+      dma: Dma::new(),
+      restrict_access: true,
+      stat_mode_interrupt: 0,
+      stat_line: false,
+      cpu_cycles: 0
+    };
+    // The boot ROM leaves the LCD on with the background showing
+    memory.memory[0xff40] = 0x91;
+    memory
+  }
+
+  // Advances everything on the bus that runs off the system clock
+  pub fn tick(&mut self, cycles: i64) {
+    let div = self.timer.counter();
+    if self.timer.step(cycles) {
+      self.memory[0xff0f] |= 0b00000100; // TIMER
+    }
+    self.div_changed(div);
+    self.apu.step(cycles);
+    let source = self.dma.source();
+    for offset in self.dma.step(cycles) {
+      let value = self.dma_read(source + offset);
+      self.memory[0xfe00 + offset as usize] = value;
     }
   }
 
-  // @Performance Read and write can use unsafe operations to index
+  // The CPU's side of the bus. Each access takes an M-cycle, and everything
+  // else is clocked through that M-cycle before the access happens. That way
+  // the CPU sees the timer, DMA and APU as they are partway through an
+  // instruction, not as they were before it started.
+  pub fn cycle_read(&mut self, address: u16) -> u8 {
+    self.cycle_idle();
+    self.read_byte(address)
+  }
 
-  pub fn write_byte(&mut self, address: u16, value: u8) {
-    self.memory[translate(address)] = value;
+  pub fn cycle_write(&mut self, address: u16, value: u8) {
+    self.cycle_idle();
+    self.write_byte(address, value);
+  }
+
+  // An M-cycle where the CPU is busy with something other than the bus
+  pub fn cycle_idle(&mut self) {
+    self.tick(4);
+    self.cpu_cycles += 4;
+  }
+
+  // Clocks whatever part of the instruction's cycles the CPU didn't go
+  // through the bus for
+  pub fn finish_instruction(&mut self, cycles: i64) {
+    debug_assert!(self.cpu_cycles <= cycles, "clocked {} cycles of a {} cycle instruction", self.cpu_cycles, cycles);
+    let remaining = cycles - self.cpu_cycles;
+    self.cpu_cycles = 0;
+    self.tick(remaining);
+  }
+
+  // The APU's frame sequencer steps whenever bit 4 of DIV falls, which
+  // includes DIV being reset by a write
+  fn div_changed(&mut self, before: u16) {
+    if before & FRAME_SEQUENCER_BIT != 0 && self.timer.counter() & FRAME_SEQUENCER_BIT == 0 {
+      self.apu.clock_frame_sequencer();
+    }
+  }
+
+  // Refreshes the LY=LYC coincidence bit of STAT and requests LCD_STAT if
+  // the interrupt line went high. All the enabled sources are ORed into one
+  // line, so while any of them holds it high the others can't trigger
+  // another interrupt ("STAT blocking"). The PPU calls this whenever LY or
+  // the mode changes, and writes to STAT and LYC call it too.
+  pub fn update_stat(&mut self) {
+    if self.memory[0xff40] & 0x80 == 0 {
+      return;
+    }
+    let mut stat = self.memory[0xff41] & !0b00000100;
+    if self.memory[0xff44] == self.memory[0xff45] {
+      stat |= 0b00000100; // COINCIDENCE
+    }
+    self.memory[0xff41] = stat;
+    let line = (stat & 0b01000000 != 0 && stat & 0b00000100 != 0)
+      || stat & self.stat_mode_interrupt != 0;
+    if line && !self.stat_line {
+      self.memory[0xff0f] |= 0b00000010; // LCD_STAT
+    }
+    self.stat_line = line;
+  }
+
+  // The DMA reads straight off the bus, ignoring what the PPU is doing.
+  // Sources past 0xDFFF see work RAM again, just like the echo.
+  fn dma_read(&self, address: u16) -> u8 {
+    if address <= 0x7FFF {
+      self.cartridge.read_rom(address)
+    } else if address >= 0xA000 && address <= 0xBFFF {
+      self.cartridge.read_ram(address)
+    } else if address >= 0xE000 {
+      self.memory[address as usize - 0x2000]
+    } else {
+      self.memory[address as usize]
+    }
   }
 
-  pub fn write_short(&mut self, address: u16, value: u16) {
-    // This is basically un-needed because rust does this in debug mode already
-    // but I just want to remind myself
-    debug_assert!(address != 65535);
-    self.memory[translate(address)] = value.lo();
-    self.memory[translate(address + 1)] = value.hi();
+  pub fn set_button(&mut self, button: Button, pressed: bool) {
+    if self.joypad.set_button(button, pressed) {
+      self.memory[0xff0f] |= 0b00010000; // JOYPAD
+    }
+  }
+
+  // During DMA only the IO registers and HRAM can be reached. Otherwise the
+  // PPU has OAM to itself during OAM search and pixel transfer, and VRAM
+  // during pixel transfer.
+  fn accessible(&self, address: u16) -> bool {
+    // A running DMA has the bus, whether or not we restrict the PPU's areas
+    if self.dma.active() && address < 0xFF00 {
+      return false;
+    }
+    if !self.restrict_access {
+      return true;
+    }
+    let mode = self.memory[0xff41] & 0b00000011;
+    if address >= 0x8000 && address <= 0x9FFF {
+      mode != 3
+    } else if address >= 0xFE00 && address <= 0xFE9F {
+      mode != 2 && mode != 3
+    } else {
+      true
+    }
+  }
+
+  // @Performance Read and write can use unsafe operations to index
+
+  pub fn write_byte(&mut self, address: u16, value: u8) {
+    if !self.accessible(address) {
+      return;
+    }
+    if address <= 0x7FFF {
+      self.cartridge.write_rom(address, value);
+    } else if address >= 0xA000 && address <= 0xBFFF {
+      self.cartridge.write_ram(address, value);
+    } else if address == 0xFF00 {
+      if self.joypad.write(value) {
+        self.memory[0xff0f] |= 0b00010000; // JOYPAD
+      }
+    } else if address >= 0xFF04 && address <= 0xFF07 {
+      let div = self.timer.counter();
+      self.timer.write(address, value);
+      self.div_changed(div);
+    } else if address >= 0xFF10 && address <= 0xFF3F {
+      self.apu.write(address, value);
+    } else if address == 0xFF40 {
+      // Turning the LCD off resets LY and the mode right away, the PPU only
+      // notices on its next step
+      if self.memory[0xff40] & 0x80 != 0 && value & 0x80 == 0 {
+        self.memory[0xff44] = 0;
+        self.memory[0xff41] &= !0b00000011;
+        self.stat_line = false;
+      }
+      self.memory[0xff40] = value;
+    } else if address == 0xFF46 {
+      self.dma.write(value);
+    } else if address == 0xFF41 {
+      // The mode and coincidence bits belong to the PPU
+      self.memory[0xff41] = (value & 0b01111000) | (self.memory[0xff41] & 0b00000111);
+      self.update_stat();
+    } else if address == 0xFF45 {
+      self.memory[0xff45] = value;
+      self.update_stat();
+    } else {
+      self.memory[translate(address)] = value;
+    }
   }
 
   pub fn read_byte(&self, address: u16) -> u8 {
-    if address >= 0xFEA0 && address <= 0xFEFF {
+    if !self.accessible(address) {
+      return 0xff;
+    }
+    if address <= 0x7FFF {
+      self.cartridge.read_rom(address)
+    } else if address >= 0xA000 && address <= 0xBFFF {
+      self.cartridge.read_ram(address)
+    } else if address >= 0xFEA0 && address <= 0xFEFF {
       0xff
+    } else if address == 0xFF00 {
+      self.joypad.read()
+    } else if address >= 0xFF04 && address <= 0xFF07 {
+      self.timer.read(address)
+    } else if address >= 0xFF10 && address <= 0xFF3F {
+      self.apu.read(address)
     } else if address == 0xFF0F {
       0b11100000 | self.memory[0xff0f]
+    } else if address == 0xFF41 {
+      0b10000000 | self.memory[0xff41]
+    } else if address == 0xFF46 {
+      self.dma.read()
     } else {
       self.memory[translate(address)]
     }
   }
-  
-  pub fn read_signed_byte(&self, address: u16) -> i8 {
-    self.read_byte(address) as i8
-  }
-
-  pub fn read_short(&self, address: u16) -> u16 {
-    // This is basically un-needed because rust does this in debug mode already
-    // but I just want to remind myself
-    debug_assert!(address != 65535);
-    (self.read_byte(address + 1) as u16) << 8 | self.read_byte(address) as u16
-  }
 }
 
 // Translates from virtual gameboy addresses to our array indexing
@@ -0,0 +1,68 @@
+use image::Rgba;
+
+// What the four DMG shades look like on the host, lightest (shade 0) first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+  colors: [[u8; 3]; 4]
+}
+
+impl Palette {
+  // The pea soup green of the original DMG screen
+  pub fn classic_green() -> Palette {
+    Palette {
+      colors: [
+        [0x9b, 0xbc, 0x0f],
+        [0x8b, 0xac, 0x0f],
+        [0x30, 0x62, 0x30],
+        [0x0f, 0x38, 0x0f]
+      ]
+    }
+  }
+
+  // The Game Boy Pocket's much less green screen
+  pub fn pocket_gray() -> Palette {
+    Palette {
+      colors: [
+        [0xe0, 0xdb, 0xcd],
+        [0xa8, 0x9f, 0x94],
+        [0x70, 0x6b, 0x66],
+        [0x2b, 0x2b, 0x26]
+      ]
+    }
+  }
+
+  // Either the name of a built in palette ("green" or "gray") or four
+  // custom colors written as hex triplets, lightest first, with or without
+  // the #:
+  //   #e0f8d0 #88c070 #346856 #081820
+  pub fn parse(value: &str) -> Result<Palette, String> {
+    match value {
+      "green" => return Ok(Palette::classic_green()),
+      "gray" | "grey" => return Ok(Palette::pocket_gray()),
+      _ => ()
+    }
+    let colors = value.split_whitespace().collect::<Vec<_>>();
+    if colors.len() != 4 {
+      return Err(format!("expected green, gray or four colors but got `{}`", value));
+    }
+    let mut palette = Palette { colors: [[0; 3]; 4] };
+    for (shade, color) in colors.iter().enumerate() {
+      palette.colors[shade] = parse_color(color)?;
+    }
+    Ok(palette)
+  }
+
+  pub fn color(&self, shade: u8) -> Rgba<u8> {
+    let color = self.colors[shade as usize];
+    Rgba([color[0], color[1], color[2], 255])
+  }
+}
+
+fn parse_color(color: &str) -> Result<[u8; 3], String> {
+  let hex = if color.starts_with('#') { &color[1..] } else { color };
+  let value = match u32::from_str_radix(hex, 16) {
+    Ok(value) if hex.len() == 6 => value,
+    _ => return Err(format!("`{}` is not a color like #rrggbb", color))
+  };
+  Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8])
+}
@@ -1,5 +1,6 @@
 use memory::Memory;
 use image::{ImageBuffer, Rgba};
+use palette::Palette;
 use glium;
 use std::vec::Vec;
 
@@ -26,6 +27,39 @@ bitflags! {
     }
 }
 
+// 0xFF41, the low three bits are read only
+bitflags! {
+    struct STAT: u8 {
+        const COINCIDENCE_INTERRUPT = 0b01000000;
+        const OAM_INTERRUPT         = 0b00100000;
+        const VBLANK_INTERRUPT      = 0b00010000;
+        const HBLANK_INTERRUPT      = 0b00001000;
+        const COINCIDENCE           = 0b00000100;
+    }
+}
+
+// Byte 3 of an OAM entry
+bitflags! {
+    struct SpriteFlags: u8 {
+        const BG_PRIORITY = 0b10000000;
+        const Y_FLIP      = 0b01000000;
+        const X_FLIP      = 0b00100000;
+        const OBP1        = 0b00010000;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Sprite {
+  // Both offset like in OAM, the top left of the screen is (8, 16)
+  y: u8,
+  x: u8,
+  tile: u8,
+  flags: SpriteFlags
+}
+
+// The PPU gives up looking through OAM after finding this many on a line
+const SPRITES_PER_LINE: usize = 10;
+
 #[derive(Clone, Copy)]
 enum Mode {
   HBlank,
@@ -34,115 +68,341 @@ enum Mode {
   PixelTransfer
 }
 
+pub const SCREEN_WIDTH: u32 = 160;
+pub const SCREEN_HEIGHT: u32 = 144;
+
 pub struct PPU {
-  // TODO: this can and should be a (boxed) [u8; 256] not a vec
+  // TODO: this can and should be a (boxed) [u8; 160 * 144] not a vec
   frame_buffer: ImageBuffer<Rgba<u8>, Vec<u8>>,
   mode: Mode,
-  current_line: u8
+  current_line: u8,
+  // The window keeps its own line counter, which only moves on lines where
+  // the window was actually drawn. Hiding it mid-frame pauses it rather than
+  // letting it skip ahead.
+  window_line: u8,
+  // Latched once LY == WY, the window can't start before that in a frame
+  window_triggered: bool,
+  // Picked during OAM search, in drawing priority order
+  line_sprites: Vec<Sprite>,
+  // Host colors for the shades BGP/OBP0/OBP1 pick
+  palette: Palette,
+  // How long mode 3 lasts on the current line, worked out after OAM search
+  transfer_cycles: i64,
+  // Follows LCD_POWER. While off the PPU does nothing but wait to be turned
+  // back on.
+  enabled: bool,
+  // Line 0 right after turning the LCD on skips OAM search. STAT reads mode 0
+  // and the line is a little shorter than usual.
+  starting: bool,
+  // The frame after turning the LCD on never reaches the screen
+  skip_frame: bool
 }
 
 impl PPU {
-  pub fn new() -> PPU {
+  pub fn new(palette: Palette) -> PPU {
     PPU {
-      frame_buffer: ImageBuffer::new(256, 256),
+      frame_buffer: ImageBuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT),
       mode: Mode::OAMSearch,
-      current_line: 0
+      current_line: 0,
+      window_line: 0,
+      window_triggered: false,
+      line_sprites: Vec::with_capacity(SPRITES_PER_LINE),
+      palette: palette,
+      transfer_cycles: 172,
+      enabled: true,
+      starting: false,
+      skip_frame: false
     }
   }
 
-  pub fn draw(&mut self, memory: &Memory) -> (glium::texture::RawImage2d<u8>, u8) {
-    let control = LCDC::from_bits_truncate(memory.read_byte(0xff40));
-    let tiles = if control.contains(BG_WINDOW_TILESET) {
-      &memory.memory[0x8000..0x9000]
-    } else {
-      &memory.memory[0x8800..0x9800]
-    };
-    let bg_tile_map = if control.contains(BG_TILE_MAP) {
-      &memory.memory[0x9c00..0xa000]
-    } else {
-      &memory.memory[0x9800..0x9c00]
-    };
-    let mut cur_x = 0;
-    let mut cur_y = 0;
-    for row in bg_tile_map.chunks(32) {
-      for index in row {
-        let real_index = if control.contains(BG_WINDOW_TILESET) {
-          *index as usize * 16
-        } else {
-          ((*index) as i8 as i16 + 128) as usize * 16
-        };
-        let tile = &tiles[real_index..real_index+16];
-        for line in tile.chunks(2) {
-          self.frame_buffer.put_pixel(cur_x,     cur_y, to_pixel(line[0] & 0x80 >> 6 | line[1] & 0x80 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 1, cur_y, to_pixel(line[0] & 0x40 >> 6 | line[1] & 0x40 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 2, cur_y, to_pixel(line[0] & 0x20 >> 6 | line[1] & 0x20 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 3, cur_y, to_pixel(line[0] & 0x10 >> 6 | line[1] & 0x10 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 4, cur_y, to_pixel(line[0] & 0x08 >> 6 | line[1] & 0x08 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 5, cur_y, to_pixel(line[0] & 0x04 >> 6 | line[1] & 0x04 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 6, cur_y, to_pixel(line[0] & 0x02 >> 6 | line[1] & 0x02 >> 7));
-          self.frame_buffer.put_pixel(cur_x + 7, cur_y, to_pixel(line[0] & 0x01 >> 6 | line[1] & 0x01 >> 7));
-          cur_y += 1;
+  pub fn draw(&self) -> glium::texture::RawImage2d<u8> {
+    glium::texture::RawImage2d::from_raw_rgba_reversed(self.frame_buffer.clone().into_raw(), (SCREEN_WIDTH, SCREEN_HEIGHT))
+  }
+
+  // Renders current_line into the frame buffer. The registers are read here,
+  // once per line, so games can change them between lines.
+  fn render_line(&mut self, memory: &Memory) {
+    let control = LCDC::from_bits_truncate(memory.memory[0xff40]);
+    let scroll_y = memory.memory[0xff42];
+    let scroll_x = memory.memory[0xff43];
+    let line = self.current_line;
+    let window_y = memory.memory[0xff4a];
+    let window_x = memory.memory[0xff4b];
+    let bg_palette = memory.memory[0xff47];
+    let bg_tile_map = if control.contains(BG_TILE_MAP) { 0x9c00 } else { 0x9800 };
+    let window_tile_map = if control.contains(WINDOW_TILE_MAP) { 0x9c00 } else { 0x9800 };
+
+    if line == window_y {
+      self.window_triggered = true;
+    }
+    // WX is the window's left edge plus 7. Anything past 166 is off screen,
+    // below 7 the window starts cut off on the left. On the DMG turning off
+    // the background turns off the window too.
+    let window_visible = control.contains(BG_ENABLED) && control.contains(WINDOW_ENABLE) &&
+      self.window_triggered && window_x <= 166;
+
+    // The background is 256x256 and wraps around in both directions
+    let bg_y = line.wrapping_add(scroll_y);
+    for x in 0..SCREEN_WIDTH as u8 {
+      let color = if window_visible && x as u16 + 7 >= window_x as u16 {
+        let win_x = (x as u16 + 7 - window_x as u16) as u8;
+        tile_map_pixel(memory, control, window_tile_map, win_x, self.window_line)
+      } else if control.contains(BG_ENABLED) {
+        tile_map_pixel(memory, control, bg_tile_map, x.wrapping_add(scroll_x), bg_y)
+      } else {
+        0
+      };
+      let shade = match self.sprite_pixel(memory, control, x, color) {
+        Some(shade) => shade,
+        // With the background off it's plain white, whatever BGP says
+        None if !control.contains(BG_ENABLED) => 0,
+        None => palette_shade(bg_palette, color)
+      };
+      let pixel = self.palette.color(shade);
+      self.frame_buffer.put_pixel(x as u32, line as u32, pixel);
+    }
+
+    if window_visible {
+      self.window_line += 1;
+    }
+  }
+
+  // Finds up to 10 sprites that overlap current_line, in OAM order, then
+  // puts them in the order the DMG draws them: the smallest X wins, and for
+  // equal X the earlier OAM entry wins.
+  fn oam_search(&mut self, memory: &Memory) {
+    let control = LCDC::from_bits_truncate(memory.memory[0xff40]);
+    let height = if control.contains(SPRITE_SIZE) { 16 } else { 8 };
+    let line = self.current_line as u16 + 16;
+    self.line_sprites.clear();
+    for entry in memory.memory[0xfe00..0xfea0].chunks(4) {
+      let y = entry[0] as u16;
+      if line >= y && line < y + height {
+        self.line_sprites.push(Sprite {
+          y: entry[0],
+          x: entry[1],
+          tile: entry[2],
+          flags: SpriteFlags::from_bits_truncate(entry[3])
+        });
+        if self.line_sprites.len() == SPRITES_PER_LINE {
+          break;
+        }
+      }
+    }
+    // Stable, so OAM order breaks the ties
+    self.line_sprites.sort_by_key(|sprite| sprite.x);
+  }
+
+  // Mode 3 takes 172 cycles at the least. The fetcher throws away SCX % 8
+  // pixels at the start of the line, restarting for the window costs 6
+  // more, and every sprite stalls it for 6 to 11 while its tile is fetched.
+  fn pixel_transfer_cycles(&self, memory: &Memory) -> i64 {
+    let control = LCDC::from_bits_truncate(memory.memory[0xff40]);
+    let scroll_x = memory.memory[0xff43];
+    let window_y = memory.memory[0xff4a];
+    let window_x = memory.memory[0xff4b];
+    let window_visible = control.contains(BG_ENABLED) && control.contains(WINDOW_ENABLE) &&
+      (self.window_triggered || self.current_line == window_y) && window_x <= 166;
+
+    let mut cycles = 172 + (scroll_x % 8) as i64;
+    if window_visible {
+      cycles += 6;
+    }
+    if !control.contains(SPRITES_ENABLED) {
+      return cycles;
+    }
+    // Background/window tiles that a sprite has already waited on. Only the
+    // first sprite over a tile pays for the rest of that tile being fetched.
+    let mut waited_tiles: Vec<(bool, u8)> = Vec::with_capacity(SPRITES_PER_LINE);
+    for sprite in &self.line_sprites {
+      // Sprites entirely off the right edge are never fetched
+      if sprite.x >= 168 {
+        continue;
+      }
+      if sprite.x == 0 {
+        cycles += 11;
+        continue;
+      }
+      // Position of the sprite's leftmost pixel in whichever layer is under it
+      let (window, position) = if window_visible && sprite.x as u16 >= window_x as u16 + 1 {
+        (true, sprite.x.wrapping_sub(1).wrapping_sub(window_x))
+      } else {
+        (false, sprite.x.wrapping_sub(8).wrapping_add(scroll_x))
+      };
+      let tile = (window, position / 8);
+      if !waited_tiles.contains(&tile) {
+        waited_tiles.push(tile);
+        // Pixels of the tile right of the sprite, less the 2 the fetcher
+        // gets done anyway
+        let remaining = 7 - (position % 8) as i64;
+        if remaining > 2 {
+          cycles += remaining - 2;
         }
-        cur_x += 8;
-        cur_y -= 8;
       }
-      cur_y += 8;
-      cur_x = 0;
+      cycles += 6;
     }
-    let scroll_x = memory.read_byte(0xff42);
-    let scroll_y = memory.read_byte(0xff43);
-    (glium::texture::RawImage2d::from_raw_rgba_reversed(self.frame_buffer.clone().into_raw(), (256, 256)), 0)
+    cycles
+  }
+
+  // The shade of the sprite covering x on the current line, if there's one
+  // and it isn't hidden behind the background
+  fn sprite_pixel(&self, memory: &Memory, control: LCDC, x: u8, bg_color: u8) -> Option<u8> {
+    if !control.contains(SPRITES_ENABLED) {
+      return None;
+    }
+    let height = if control.contains(SPRITE_SIZE) { 16 } else { 8 };
+    let screen_x = x as u16 + 8;
+    for sprite in &self.line_sprites {
+      if screen_x < sprite.x as u16 || screen_x >= sprite.x as u16 + 8 {
+        continue;
+      }
+      let mut sprite_x = (screen_x - sprite.x as u16) as u8;
+      let mut sprite_y = (self.current_line as u16 + 16 - sprite.y as u16) as u8;
+      if sprite.flags.contains(X_FLIP) {
+        sprite_x = 7 - sprite_x;
+      }
+      if sprite.flags.contains(Y_FLIP) {
+        sprite_y = height - 1 - sprite_y;
+      }
+      // 8x16 sprites ignore the low bit of the tile number, the bottom half
+      // is just the next tile
+      let tile = if height == 16 { sprite.tile & 0xfe } else { sprite.tile };
+      let color = tile_pixel(memory, 0x8000 + tile as usize * 16, sprite_x, sprite_y);
+      // Color 0 is transparent, so the next sprite gets a chance
+      if color == 0 {
+        continue;
+      }
+      // Even when this sprite loses to the background it still hides any
+      // lower priority sprites under it
+      if sprite.flags.contains(BG_PRIORITY) && bg_color != 0 {
+        return None;
+      }
+      let palette = if sprite.flags.contains(OBP1) {
+        memory.memory[0xff49]
+      } else {
+        memory.memory[0xff48]
+      };
+      return Some(palette_shade(palette, color));
+    }
+    None
   }
 
   pub fn step(&mut self, memory: &mut Memory) -> i64 {
+    let power = LCDC::from_bits_truncate(memory.memory[0xff40]).contains(LCD_POWER);
+    if !power {
+      if self.enabled {
+        self.turn_off(memory);
+      }
+      return 4;
+    } else if !self.enabled {
+      self.turn_on(memory);
+      return 4;
+    }
+
     match self.mode {
       Mode::OAMSearch => {
-        self.mode = Mode::PixelTransfer;
-        memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::PixelTransfer as u8;
-        80
+        let cycles = if self.starting { 76 } else { 80 };
+        self.starting = false;
+        self.oam_search(memory);
+        self.transfer_cycles = self.pixel_transfer_cycles(memory);
+        self.set_mode(memory, Mode::PixelTransfer);
+        cycles
       },
       Mode::PixelTransfer => {
-        self.mode = Mode::HBlank;
-        memory.memory[0xff41] = memory.memory[0xff41] & 0xFC;
-        172 // This number is WRONG, in actuality this depends on stuff
+        if !self.skip_frame {
+          self.render_line(memory);
+        }
+        self.set_mode(memory, Mode::HBlank);
+        self.transfer_cycles
       },
       Mode::HBlank => {
         self.current_line += 1;
+        memory.memory[0xff44] = self.current_line;
         if self.current_line == 144 {
-          self.mode = Mode::VBlank;
-          memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::VBlank as u8;
-          memory.memory[0xff40] |= VBLANK.bits();
+          memory.memory[0xff0f] |= VBLANK.bits();
+          self.set_mode(memory, Mode::VBlank);
         } else {
-          self.mode = Mode::OAMSearch;
-          memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::OAMSearch as u8;
+          self.set_mode(memory, Mode::OAMSearch);
         }
-        memory.memory[0xff44] = self.current_line;
-        204
+        // Mode 3 and HBlank always add up to the same 376 cycles
+        376 - self.transfer_cycles
       },
       Mode::VBlank => {
         self.current_line += 1;
         if self.current_line == 154 {
           self.current_line = 0;
-          self.mode = Mode::OAMSearch;
-          memory.memory[0xff41] = (memory.memory[0xff41] & 0xFC) | Mode::OAMSearch as u8;
-          memory.memory[0xff40] &= !VBLANK.bits();
+          self.window_line = 0;
+          self.window_triggered = false;
+          self.skip_frame = false;
+          memory.memory[0xff44] = self.current_line;
+          self.set_mode(memory, Mode::OAMSearch);
+        } else {
+          memory.memory[0xff44] = self.current_line;
+          self.update_stat(memory);
         }
-        memory.memory[0xff44] = self.current_line;
         456 // this should vary based on line
       }
     }
   }
 
+  // LY drops straight to 0 and STAT reports mode 0. The screen goes blank,
+  // which on a DMG is a little lighter than shade 0, but close enough.
+  fn turn_off(&mut self, memory: &mut Memory) {
+    self.enabled = false;
+    self.current_line = 0;
+    self.window_line = 0;
+    self.window_triggered = false;
+    self.mode = Mode::HBlank;
+    memory.memory[0xff44] = 0;
+    memory.memory[0xff41] &= !0b00000011;
+    let blank = self.palette.color(0);
+    for pixel in self.frame_buffer.pixels_mut() {
+      *pixel = blank;
+    }
+  }
+
+  fn turn_on(&mut self, memory: &mut Memory) {
+    self.enabled = true;
+    self.starting = true;
+    self.skip_frame = true;
+    self.set_mode(memory, Mode::OAMSearch);
+  }
+
+  fn set_mode(&mut self, memory: &mut Memory, mode: Mode) {
+    self.mode = mode;
+    self.update_stat(memory);
+  }
+
+  // Refreshes the mode bits of STAT and which of the mode interrupts can
+  // fire, then lets Memory work out the interrupt line
+  fn update_stat(&mut self, memory: &mut Memory) {
+    let mut stat = memory.memory[0xff41] & !0b00000011;
+    if !self.starting {
+      stat |= self.mode as u8;
+    }
+    memory.memory[0xff41] = stat;
+    memory.stat_mode_interrupt = match self.mode {
+      Mode::HBlank => HBLANK_INTERRUPT.bits(),
+      Mode::VBlank => VBLANK_INTERRUPT.bits(),
+      Mode::OAMSearch if !self.starting => OAM_INTERRUPT.bits(),
+      _ => 0
+    };
+    memory.update_stat();
+  }
+
   pub fn estimate_clock_cycles(&mut self) -> i64 {
+    if !self.enabled {
+      return 4;
+    }
     match self.mode {
       Mode::OAMSearch => {
-        80
+        if self.starting { 76 } else { 80 }
       },
       Mode::PixelTransfer => {
-        172
+        self.transfer_cycles
       },
       Mode::HBlank => {
-        204
+        376 - self.transfer_cycles
       },
       Mode::VBlank => {
         456
@@ -151,21 +411,31 @@ impl PPU {
   }
 }
 
-fn to_pixel(bits: u8) -> Rgba<u8> {
-  // TODO: do palette lookup
-  match bits {
-    0 => {
-      Rgba([0u8, 0, 0, 255])
-    },
-    1 => {
-      Rgba([85u8, 85, 85, 255])
-    },
-    2 => {
-      Rgba([170u8, 170, 170, 255])
-    },
-    3 => {
-      Rgba([255u8, 255, 255, 255])
-    },
-    _ => unreachable!()
-  }
-}
\ No newline at end of file
+// Color number (0-3) of the pixel at x,y of a 32x32 tile map
+fn tile_map_pixel(memory: &Memory, control: LCDC, tile_map: usize, x: u8, y: u8) -> u8 {
+  let index = memory.memory[tile_map + (y as usize / 8) * 32 + x as usize / 8];
+  // 0x8000 addressing uses the index as is, 0x8800 addressing treats it as
+  // signed and counts from 0x9000
+  let tile = if control.contains(BG_WINDOW_TILESET) {
+    0x8000 + index as usize * 16
+  } else {
+    (0x9000 + index as i8 as isize * 16) as usize
+  };
+  tile_pixel(memory, tile, x % 8, y % 8)
+}
+
+// Each row of a tile is two bytes, the first has the low bit of every pixel
+// and the second the high bit. Bit 7 is the leftmost pixel.
+fn tile_pixel(memory: &Memory, tile: usize, x: u8, y: u8) -> u8 {
+  let row = tile + y as usize * 2;
+  let bit = 7 - x;
+  let lo = (memory.memory[row] >> bit) & 1;
+  let hi = (memory.memory[row + 1] >> bit) & 1;
+  hi << 1 | lo
+}
+
+// Palette registers pack a shade (0-3) for each color number, color 0 in
+// the low two bits
+fn palette_shade(palette: u8, color: u8) -> u8 {
+  (palette >> (color * 2)) & 0b11
+}
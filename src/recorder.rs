@@ -0,0 +1,142 @@
+use apu::{CHANNELS, CHANNEL_NAMES, CLOCK_RATE};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Records the APU output to 16-bit WAV files for a set number of video
+// frames: either the stereo mix into one file, or every channel into a mono
+// file of its own.
+
+// What the APU resamples recordings to, whatever the sound card runs at
+pub const SAMPLE_RATE: u32 = 48000;
+
+// 154 lines of 456 cycles
+const CYCLES_PER_FRAME: i64 = 70224;
+
+pub struct Recorder {
+  // One for the mix, or one per channel
+  writers: Vec<WavWriter>,
+  // Sample frames still to write to each file
+  remaining: Vec<usize>
+}
+
+impl Recorder {
+  // Writes to <rom>.wav
+  pub fn mix(rom_path: &str, frames: u32) -> io::Result<Recorder> {
+    let path = Path::new(rom_path).with_extension("wav");
+    let writer = WavWriter::create(&path, 2)?;
+    info!("Recording the mix to {}", path.display());
+    Ok(Recorder::new(vec![writer], frames))
+  }
+
+  // Writes to <rom>-square1.wav and so on
+  pub fn channels(rom_path: &str, frames: u32) -> io::Result<Recorder> {
+    let rom_path = Path::new(rom_path);
+    let stem = rom_path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut writers = Vec::with_capacity(CHANNELS);
+    for name in CHANNEL_NAMES.iter() {
+      let path = rom_path.with_file_name(format!("{}-{}.wav", stem, name));
+      writers.push(WavWriter::create(&path, 1)?);
+      info!("Recording {} to {}", name, path.display());
+    }
+    Ok(Recorder::new(writers, frames))
+  }
+
+  fn new(writers: Vec<WavWriter>, frames: u32) -> Recorder {
+    let length = (frames as i64 * CYCLES_PER_FRAME * SAMPLE_RATE as i64 / CLOCK_RATE) as usize;
+    Recorder {
+      remaining: vec![length; writers.len()],
+      writers: writers
+    }
+  }
+
+  // One buffer per APU recording output. The mix's two go into one file,
+  // interleaved. Anything past the requested length is dropped.
+  pub fn record(&mut self, outputs: &[Vec<f32>]) -> io::Result<()> {
+    let mut outputs = outputs.iter();
+    for (writer, remaining) in self.writers.iter_mut().zip(self.remaining.iter_mut()) {
+      let channels = outputs.by_ref().take(writer.channels as usize).collect::<Vec<_>>();
+      let frames = channels.iter().map(|samples| samples.len()).min().unwrap_or(0);
+      let frames = ::std::cmp::min(*remaining, frames);
+      let mut samples = Vec::with_capacity(frames * channels.len());
+      for frame in 0..frames {
+        for channel in channels.iter() {
+          samples.push(channel[frame]);
+        }
+      }
+      writer.write(&samples)?;
+      *remaining -= frames;
+    }
+    Ok(())
+  }
+
+  pub fn done(&self) -> bool {
+    self.remaining.iter().all(|&remaining| remaining == 0)
+  }
+
+  pub fn finish(self) -> io::Result<()> {
+    for writer in self.writers {
+      writer.finish()?;
+    }
+    info!("Recording finished");
+    Ok(())
+  }
+}
+
+struct WavWriter {
+  file: BufWriter<File>,
+  channels: u16,
+  data_size: u32
+}
+
+impl WavWriter {
+  // The sizes in the header are filled in by finish
+  fn create(path: &Path, channels: u16) -> io::Result<WavWriter> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let block_align = channels * 2;
+    file.write_all(b"RIFF")?;
+    write_u32(&mut file, 0)?;
+    file.write_all(b"WAVE")?;
+    file.write_all(b"fmt ")?;
+    write_u32(&mut file, 16)?;
+    write_u16(&mut file, 1)?; // PCM
+    write_u16(&mut file, channels)?;
+    write_u32(&mut file, SAMPLE_RATE)?;
+    write_u32(&mut file, SAMPLE_RATE * block_align as u32)?;
+    write_u16(&mut file, block_align)?;
+    write_u16(&mut file, 16)?;
+    file.write_all(b"data")?;
+    write_u32(&mut file, 0)?;
+    Ok(WavWriter {
+      file: file,
+      channels: channels,
+      data_size: 0
+    })
+  }
+
+  fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+    for sample in samples {
+      let value = (sample.max(-1.0).min(1.0) * ::std::i16::MAX as f32) as i16;
+      write_u16(&mut self.file, value as u16)?;
+    }
+    self.data_size += samples.len() as u32 * 2;
+    Ok(())
+  }
+
+  fn finish(mut self) -> io::Result<()> {
+    self.file.seek(SeekFrom::Start(4))?;
+    write_u32(&mut self.file, 36 + self.data_size)?;
+    self.file.seek(SeekFrom::Start(40))?;
+    write_u32(&mut self.file, self.data_size)?;
+    self.file.flush()
+  }
+}
+
+fn write_u16<W: Write>(writer: &mut W, value: u16) -> io::Result<()> {
+  writer.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+  writer.write_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8])
+}
@@ -1,14 +1,18 @@
-use memory::Memory;
+use mbc;
+use mbc::Mbc;
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::Read;
 
-enum Cart {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cart {
   RomOnly = 0x00,
   RomMBC1,
   RomMBC1Ram,
   RomMBC1RamBatt,
-  RomMBC2,
+  RomMBC2 = 0x05,
   RomMBC2Batt,
   RomRam = 0x08,
   RomRamBatt,
@@ -32,9 +36,286 @@ enum Cart {
   HudsonHuC1 = 0xFF
 }
 
-pub fn load_rom(memory: &mut Memory, path: &str) -> Result<(), io::Error> {
-  let mut file = File::open(path)?;
-  let mut buf = &mut memory.memory[0..0x8000];
-  file.read_exact(buf)
+impl Cart {
+  pub fn from_byte(byte: u8) -> Option<Cart> {
+    match byte {
+      0x00 => Some(Cart::RomOnly),
+      0x01 => Some(Cart::RomMBC1),
+      0x02 => Some(Cart::RomMBC1Ram),
+      0x03 => Some(Cart::RomMBC1RamBatt),
+      0x05 => Some(Cart::RomMBC2),
+      0x06 => Some(Cart::RomMBC2Batt),
+      0x08 => Some(Cart::RomRam),
+      0x09 => Some(Cart::RomRamBatt),
+      0x0B => Some(Cart::RomMMM1),
+      0x0C => Some(Cart::RomMM1Sram),
+      0x0D => Some(Cart::RomMM1SramBatt),
+      0x0F => Some(Cart::RomMBC3TimerBatt),
+      0x10 => Some(Cart::RomMBC3TimerRamBatt),
+      0x11 => Some(Cart::RomMBC3),
+      0x12 => Some(Cart::RomMBC3Ram),
+      0x13 => Some(Cart::RomMBC3RamBatt),
+      0x19 => Some(Cart::RomMBC5),
+      0x1A => Some(Cart::RomMBC5Ram),
+      0x1B => Some(Cart::RomMBC5RamBatt),
+      0x1C => Some(Cart::RomMBC5Rumble),
+      0x1D => Some(Cart::RomMBC5RumbleSram),
+      0x1E => Some(Cart::RomMBC5RumbleSramBatt),
+      0x1F => Some(Cart::PocketCamera),
+      0xFD => Some(Cart::BandaiTAMA5),
+      0xFE => Some(Cart::HudsonHuC3),
+      0xFF => Some(Cart::HudsonHuC1),
+      _ => None
+    }
+  }
+
+  pub fn has_battery(&self) -> bool {
+    match *self {
+      Cart::RomMBC1RamBatt | Cart::RomMBC2Batt | Cart::RomRamBatt | Cart::RomMM1SramBatt |
+      Cart::RomMBC3TimerBatt | Cart::RomMBC3TimerRamBatt | Cart::RomMBC3RamBatt |
+      Cart::RomMBC5RamBatt | Cart::RomMBC5RumbleSramBatt | Cart::PocketCamera |
+      Cart::HudsonHuC3 | Cart::HudsonHuC1 => true,
+      _ => false
+    }
+  }
+
+  pub fn has_timer(&self) -> bool {
+    match *self {
+      Cart::RomMBC3TimerBatt | Cart::RomMBC3TimerRamBatt => true,
+      _ => false
+    }
+  }
+
+  pub fn has_rumble(&self) -> bool {
+    match *self {
+      Cart::RomMBC5Rumble | Cart::RomMBC5RumbleSram | Cart::RomMBC5RumbleSramBatt => true,
+      _ => false
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgbSupport {
+  None,
+  // Works on both the DMG and the CGB
+  Enhanced,
+  // Only works on the CGB
+  Only
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Destination {
+  Japanese,
+  Overseas
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Licensee {
+  // 0x014B
+  Old(u8),
+  // 0x0144 - 0x0145, used when the old code is 0x33
+  New(String)
+}
+
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+  pub title: String,
+  pub manufacturer_code: Option<String>,
+  pub cgb: CgbSupport,
+  pub sgb: bool,
+  pub cart: Cart,
+  // Both in bytes
+  pub rom_size: usize,
+  pub ram_size: usize,
+  pub destination: Destination,
+  pub licensee: Licensee,
+  pub version: u8,
+  pub header_checksum: u8,
+  pub global_checksum: u16
+}
+
+#[derive(Debug)]
+pub enum RomError {
+  Io(io::Error),
+  // The file is too small to hold a header (0x0150 bytes)
+  TooSmall(usize),
+  UnknownCartType(u8),
+  UnknownRomSize(u8),
+  UnknownRamSize(u8),
+  // Parsed fine, but there's no controller for it yet
+  UnsupportedCart(Cart),
+  HeaderChecksum { expected: u8, actual: u8 },
+  GlobalChecksum { expected: u16, actual: u16 }
+}
+
+impl From<io::Error> for RomError {
+  fn from(err: io::Error) -> RomError {
+    RomError::Io(err)
+  }
+}
+
+impl fmt::Display for RomError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      RomError::Io(ref err) => write!(f, "Could not read ROM: {}", err),
+      RomError::TooSmall(size) => write!(f, "ROM is only {} bytes, too small to hold a header", size),
+      RomError::UnknownCartType(byte) => write!(f, "Unknown cartridge type {:02x}", byte),
+      RomError::UnknownRomSize(byte) => write!(f, "Unknown ROM size {:02x}", byte),
+      RomError::UnknownRamSize(byte) => write!(f, "Unknown RAM size {:02x}", byte),
+      RomError::UnsupportedCart(cart) => write!(f, "Cartridge type {:?} is not supported", cart),
+      RomError::HeaderChecksum { expected, actual } => {
+        write!(f, "Header checksum mismatch, expected {:02x} but got {:02x}", expected, actual)
+      },
+      RomError::GlobalChecksum { expected, actual } => {
+        write!(f, "Global checksum mismatch, expected {:04x} but got {:04x}", expected, actual)
+      }
+    }
+  }
+}
+
+impl Error for RomError {
+  fn description(&self) -> &str {
+    match *self {
+      RomError::Io(ref err) => err.description(),
+      RomError::TooSmall(_) => "ROM too small",
+      RomError::UnknownCartType(_) => "unknown cartridge type",
+      RomError::UnknownRomSize(_) => "unknown ROM size",
+      RomError::UnknownRamSize(_) => "unknown RAM size",
+      RomError::UnsupportedCart(_) => "unsupported cartridge type",
+      RomError::HeaderChecksum { .. } => "header checksum mismatch",
+      RomError::GlobalChecksum { .. } => "global checksum mismatch"
+    }
+  }
 }
 
+impl CartridgeHeader {
+  pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, RomError> {
+    if rom.len() < 0x150 {
+      return Err(RomError::TooSmall(rom.len()));
+    }
+
+    // Check this first, if it's wrong nothing below can be trusted
+    let expected = rom[0x14D];
+    let actual = header_checksum(rom);
+    if expected != actual {
+      return Err(RomError::HeaderChecksum { expected: expected, actual: actual });
+    }
+
+    let cgb = match rom[0x143] {
+      0xC0 => CgbSupport::Only,
+      0x80 => CgbSupport::Enhanced,
+      _ => CgbSupport::None
+    };
+
+    // Newer carts carved the manufacturer code and CGB flag out of the end of the title
+    let manufacturer_code = if cgb != CgbSupport::None {
+      let code = &rom[0x13F..0x143];
+      if code.iter().all(|&b| (b >= b'A' && b <= b'Z') || (b >= b'0' && b <= b'9')) {
+        Some(String::from_utf8_lossy(code).into_owned())
+      } else {
+        None
+      }
+    } else {
+      None
+    };
+    let title_end = if manufacturer_code.is_some() {
+      0x13F
+    } else if cgb != CgbSupport::None {
+      0x143
+    } else {
+      0x144
+    };
+    let title = rom[0x134..title_end].iter()
+      .take_while(|b| **b != 0)
+      .filter(|b| **b >= 0x20 && **b < 0x7F)
+      .map(|b| *b as char)
+      .collect::<String>()
+      .trim()
+      .to_string();
+
+    let cart = match Cart::from_byte(rom[0x147]) {
+      Some(cart) => cart,
+      None => return Err(RomError::UnknownCartType(rom[0x147]))
+    };
+
+    let rom_size = match rom[0x148] {
+      code if code <= 0x08 => 0x8000 << code,
+      0x52 => 72 * 0x4000,
+      0x53 => 80 * 0x4000,
+      0x54 => 96 * 0x4000,
+      code => return Err(RomError::UnknownRomSize(code))
+    };
+
+    let ram_size = match rom[0x149] {
+      0x00 => 0,
+      0x01 => 0x800,
+      0x02 => 0x2000,
+      0x03 => 0x8000,
+      0x04 => 0x20000,
+      0x05 => 0x10000,
+      code => return Err(RomError::UnknownRamSize(code))
+    };
+
+    let licensee = if rom[0x14B] == 0x33 {
+      Licensee::New(String::from_utf8_lossy(&rom[0x144..0x146]).into_owned())
+    } else {
+      Licensee::Old(rom[0x14B])
+    };
+
+    Ok(CartridgeHeader {
+      title: title,
+      manufacturer_code: manufacturer_code,
+      cgb: cgb,
+      // The SGB functions are only enabled if the old licensee code says so as well
+      sgb: rom[0x146] == 0x03 && rom[0x14B] == 0x33,
+      cart: cart,
+      rom_size: rom_size,
+      ram_size: ram_size,
+      destination: if rom[0x14A] == 0x00 { Destination::Japanese } else { Destination::Overseas },
+      licensee: licensee,
+      version: rom[0x14C],
+      header_checksum: expected,
+      global_checksum: (rom[0x14E] as u16) << 8 | rom[0x14F] as u16
+    })
+  }
+
+  // The hardware never checks this one, so plenty of homebrew gets it wrong
+  pub fn verify_global_checksum(&self, rom: &[u8]) -> Result<(), RomError> {
+    let actual = global_checksum(rom);
+    if actual == self.global_checksum {
+      Ok(())
+    } else {
+      Err(RomError::GlobalChecksum { expected: self.global_checksum, actual: actual })
+    }
+  }
+}
+
+// 0x0134 - 0x014C, the boot ROM refuses to start if this doesn't match 0x014D
+fn header_checksum(rom: &[u8]) -> u8 {
+  rom[0x134..0x14D].iter().fold(0u8, |acc, b| acc.wrapping_sub(*b).wrapping_sub(1))
+}
+
+// Sum of every byte in the ROM except the checksum itself
+fn global_checksum(rom: &[u8]) -> u16 {
+  rom.iter()
+    .enumerate()
+    .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+    .fold(0u16, |acc, (_, b)| acc.wrapping_add(*b as u16))
+}
+
+pub fn load_rom(path: &str) -> Result<(CartridgeHeader, Box<Mbc>), RomError> {
+  let mut file = File::open(path)?;
+  let mut rom = Vec::new();
+  file.read_to_end(&mut rom)?;
+
+  let header = CartridgeHeader::parse(&rom)?;
+  if let Err(err) = header.verify_global_checksum(&rom) {
+    warn!("{}", err);
+  }
+  if rom.len() != header.rom_size {
+    warn!("Header says the ROM is {} bytes but the file is {} bytes", header.rom_size, rom.len());
+  }
+
+  let cartridge = mbc::new(&header, rom)?;
+  Ok((header, cartridge))
+}
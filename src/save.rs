@@ -0,0 +1,90 @@
+use mbc::Mbc;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Wait for the game to stop writing for this long before saving, so a game
+// filling its save slot doesn't cause one write per byte
+const AUTOSAVE_DELAY_MS: u64 = 1000;
+// But don't let a game that never stops writing go unsaved forever
+const AUTOSAVE_MAX_DELAY_MS: u64 = 10000;
+
+// Battery backed RAM, kept in <rom>.sav next to the ROM
+pub struct SaveFile {
+  path: PathBuf,
+  // First and most recent unsaved write, if there are any
+  first_write: Option<Instant>,
+  last_write: Option<Instant>
+}
+
+impl SaveFile {
+  pub fn new(rom_path: &str) -> SaveFile {
+    SaveFile {
+      path: Path::new(rom_path).with_extension("sav"),
+      first_write: None,
+      last_write: None
+    }
+  }
+
+  // A missing save file is fine, the game just starts with blank RAM
+  pub fn load(&self, cartridge: &mut Mbc) -> io::Result<()> {
+    let mut file = match File::open(&self.path) {
+      Ok(file) => file,
+      Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(err) => return Err(err)
+    };
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    cartridge.load_save_data(&data);
+    info!("Loaded {}", self.path.display());
+    Ok(())
+  }
+
+  // Call this regularly (once a frame is plenty), it saves once the game
+  // has been quiet for a bit
+  pub fn update(&mut self, cartridge: &mut Mbc) {
+    let now = Instant::now();
+    if cartridge.take_dirty() {
+      if self.first_write.is_none() {
+        self.first_write = Some(now);
+      }
+      self.last_write = Some(now);
+    }
+    let due = match (self.first_write, self.last_write) {
+      (Some(first), Some(last)) => {
+        now.duration_since(last) >= Duration::from_millis(AUTOSAVE_DELAY_MS) ||
+        now.duration_since(first) >= Duration::from_millis(AUTOSAVE_MAX_DELAY_MS)
+      },
+      _ => false
+    };
+    if due {
+      if let Err(err) = self.flush(cartridge) {
+        error!("Could not write {}: {}", self.path.display(), err);
+      }
+    }
+  }
+
+  pub fn flush(&mut self, cartridge: &Mbc) -> io::Result<()> {
+    self.first_write = None;
+    self.last_write = None;
+    match cartridge.save_data() {
+      Some(data) => write_atomic(&self.path, &data),
+      None => Ok(())
+    }
+  }
+}
+
+// Write everything to a temporary file first and rename it over the old
+// save, so a crash halfway through leaves the previous save intact
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+  let temp_path = path.with_extension("sav.tmp");
+  {
+    let mut file = File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+  }
+  fs::rename(&temp_path, path)
+}
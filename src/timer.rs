@@ -0,0 +1,123 @@
+// 0xFF04 - 0xFF07
+//
+// DIV is just the top byte of a 16-bit counter that goes up every cycle.
+// TIMA doesn't have a clock of its own, it increments whenever the counter
+// bit selected by TAC (ANDed with the enable bit) goes from 1 to 0. That's
+// why writing DIV or TAC can bump TIMA, the bit can fall because of the write.
+
+// Counter bit watched for each TAC clock select
+// 00: 4096 Hz, 01: 262144 Hz, 10: 65536 Hz, 11: 16384 Hz
+const TAC_BITS: [u16; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7];
+const TAC_ENABLE: u8 = 0b00000100;
+
+pub struct Timer {
+  counter: u16,
+  tima: u8,
+  tma: u8,
+  tac: u8,
+  // TIMA overflowed during the last M-cycle. It reads 0 until the next one,
+  // which reloads it from TMA and requests the interrupt. Writing TIMA in
+  // between cancels both.
+  reload_pending: bool,
+  // TIMA was reloaded this M-cycle. Writes to TIMA are ignored and writes
+  // to TMA go straight through to TIMA as well.
+  reloading: bool,
+  // Leftover cycles that didn't make a whole M-cycle
+  remainder: i64
+}
+
+impl Timer {
+  pub fn new() -> Timer {
+    Timer {
+      // Where the DMG boot ROM leaves it
+      counter: 0xabcc,
+      tima: 0,
+      tma: 0,
+      tac: 0,
+      reload_pending: false,
+      reloading: false,
+      remainder: 0
+    }
+  }
+
+  // Returns true if the timer interrupt should be requested
+  pub fn step(&mut self, cycles: i64) -> bool {
+    let mut interrupt = false;
+    self.remainder += cycles;
+    while self.remainder >= 4 {
+      self.remainder -= 4;
+      interrupt |= self.tick();
+    }
+    interrupt
+  }
+
+  pub fn read(&self, address: u16) -> u8 {
+    match address {
+      0xff04 => (self.counter >> 8) as u8,
+      0xff05 => self.tima,
+      0xff06 => self.tma,
+      _ => 0b11111000 | self.tac
+    }
+  }
+
+  pub fn write(&mut self, address: u16, value: u8) {
+    match address {
+      0xff04 => {
+        let before = self.signal();
+        self.counter = 0;
+        self.falling_edge(before);
+      },
+      0xff05 => {
+        if !self.reloading {
+          self.tima = value;
+          self.reload_pending = false;
+        }
+      },
+      0xff06 => {
+        self.tma = value;
+        if self.reloading {
+          self.tima = value;
+        }
+      },
+      _ => {
+        let before = self.signal();
+        self.tac = value & 0b00000111;
+        self.falling_edge(before);
+      }
+    }
+  }
+
+  // The full 16-bit counter, for anything else clocked off DIV
+  pub fn counter(&self) -> u16 {
+    self.counter
+  }
+
+  // One M-cycle
+  fn tick(&mut self) -> bool {
+    let mut interrupt = false;
+    self.reloading = false;
+    if self.reload_pending {
+      self.reload_pending = false;
+      self.reloading = true;
+      self.tima = self.tma;
+      interrupt = true;
+    }
+    let before = self.signal();
+    self.counter = self.counter.wrapping_add(4);
+    self.falling_edge(before);
+    interrupt
+  }
+
+  fn signal(&self) -> bool {
+    self.tac & TAC_ENABLE == TAC_ENABLE && self.counter & TAC_BITS[(self.tac & 0b11) as usize] != 0
+  }
+
+  fn falling_edge(&mut self, before: bool) {
+    if before && !self.signal() {
+      self.tima = self.tima.wrapping_add(1);
+      if self.tima == 0 {
+        self.reload_pending = true;
+      }
+    }
+  }
+}